@@ -21,12 +21,31 @@ use irc::streams::AsEvented;
 use mio;
 use mio::{EventLoop,Handler,Token,EventSet,PollOpt,Sender};
 use std::thread;
-use std::collections::{HashMap, BTreeSet};
+use std::collections::{HashMap, BTreeMap, BTreeSet};
 use std::io;
+use std::path::Path;
 use hyper;
+use config::{Config, NickStrategy};
+use identity;
 
 const CLIENT: Token = Token(0);
 
+// How much history to ask matrix for at a time, and how many pages to go
+// through before giving up on finding what a client asked for.
+const HISTORY_PAGE_SIZE: usize = 100;
+const HISTORY_MAX_PAGES: usize = 10;
+const CHATHISTORY_MAX_LIMIT: usize = 100;
+// Same idea, for the public room directory
+const LIST_PAGE_SIZE: usize = 100;
+const LIST_MAX_PAGES: usize = 10;
+// Including the trailing CRLF
+const MAX_LINE_LENGTH: usize = 512;
+
+// Power level at which someone shows up as a channel operator
+const OP_LEVEL: i64 = 50;
+// Power level given out by +v
+const VOICE_LEVEL: i64 = 10;
+
 #[derive(Debug)]
 pub enum Event {
     EndPoll,
@@ -37,8 +56,13 @@ pub struct Bridge {
     client: irc::streams::Client,
     matrix: matrix::client::Client,
     rooms: HashMap<matrix::model::RoomID, Room>,
+    // Rooms we've been invited to, by the channel name the client was told
+    invites: HashMap<String, matrix::model::RoomID>,
+    direct: matrix::events::DirectRooms,
     seen_events: Vec<matrix::model::EventID>,
-    last_token: String
+    last_token: String,
+    config: Config,
+    next_batch: u32
 }
 
 impl Handler for Bridge {
@@ -76,45 +100,273 @@ struct Room {
     join_rules: Option<String>,
     members: BTreeSet<matrix::model::UserID>,
     aliases: Vec<String>,
-    pending_events: Vec<(u64, matrix::events::RoomEvent)>,
+    pending_events: Vec<(u64, u64, matrix::events::RoomEvent)>,
     pending_sync: bool,
+    prev_batch: Option<String>,
+    topic: Option<(String, matrix::model::UserID, u64)>,
+    power_levels: Option<matrix::events::PowerLevels>,
+    bans: BTreeSet<matrix::model::UserID>,
+    nick_strategy: NickStrategy,
+    // Nicks picked from display names, for anyone who isn't just going by
+    // their localpart
+    nicks: BTreeMap<matrix::model::UserID, String>,
+    displaynames: BTreeMap<matrix::model::UserID, String>,
+    me: Option<matrix::model::UserID>,
+    // Who the room is a DM with, according to m.direct
+    direct_with: Option<matrix::model::UserID>,
     is_pm: bool
 }
 
 impl Room {
-    fn userid_to_irc(uid: &matrix::model::UserID) -> String {
-        format!("{}!{}@{}", uid.nickname, uid.nickname, uid.homeserver)
+    // What the user goes by when they don't have a nick from their display
+    // name.
+    fn default_nick(&self, uid: &matrix::model::UserID) -> String {
+        match self.me {
+            Some(ref me) => identity::nick(uid, &me.homeserver),
+            None => identity::nick(uid, &uid.homeserver)
+        }
+    }
+
+    fn nick(&self, uid: &matrix::model::UserID) -> String {
+        match self.nicks.get(uid) {
+            Some(nick) => nick.clone(),
+            None => self.default_nick(uid)
+        }
+    }
+
+    fn user_mask(&self, uid: &matrix::model::UserID) -> String {
+        format!("{}!{}@{}", self.nick(uid), identity::ident(uid), uid.homeserver)
+    }
+
+    fn user_from_nick(&self, nick: &str) -> Option<matrix::model::UserID> {
+        let nick = nick.to_lowercase();
+        self.members.iter().find(|u| self.nick(u).to_lowercase() == nick).cloned()
+    }
+
+    // Display names can be just about anything, so keep what's legal in a
+    // nick and swap the rest for underscores. That includes | and ^, which
+    // would otherwise let a display name pass for someone's encoded nick.
+    fn sanitize_nick(name: &str) -> String {
+        let mut nick = String::new();
+        for c in name.chars() {
+            match c {
+                'a'...'z' | 'A'...'Z' | '0'...'9' | '[' | ']' | '\\' | '`' | '_' | '{' | '}' | '-' =>
+                    nick.push(c),
+                c if c.is_whitespace() => (),
+                _ => nick.push('_')
+            }
+        }
+        match nick.chars().next() {
+            Some('0'...'9') | Some('-') => format!("_{}", nick),
+            _ => nick
+        }
+    }
+
+    // Whoever had a name first gets to keep it, everyone after gets part of
+    // their homeserver tacked on, and then all of it. The ^^ in between is
+    // never a valid escape, so these can't decode to some other user the way
+    // an identity nick with a | would.
+    fn pick_nick(&self, uid: &matrix::model::UserID, displayname: &str) -> String {
+        let base = Room::sanitize_nick(displayname);
+        if base.len() == 0 {
+            return self.default_nick(uid);
+        }
+        let server = Room::sanitize_nick(&uid.homeserver);
+        let short_server = Room::sanitize_nick(uid.homeserver.split(".").next().unwrap_or(""));
+        let mut candidates = vec![base.clone(), format!("{}^^{}", base, short_server), format!("{}^^{}", base, server)];
+        candidates.extend((2..100).map(|i| format!("{}^^{}{}", base, server, i)));
+        for candidate in candidates {
+            let lower = candidate.to_lowercase();
+            let taken = self.members.iter().any(|u| u != uid && self.nick(u).to_lowercase() == lower);
+            if !taken {
+                return candidate;
+            }
+        }
+        self.default_nick(uid)
+    }
+
+    // Our own nick belongs to the IRC client, so that never changes here.
+    fn update_nick<F>(&mut self, user: &matrix::model::UserID, displayname: Option<String>, mut callback: &mut F, ts: u64)
+            where F: FnMut(irc::protocol::Message) {
+        match displayname {
+            Some(ref name) => self.displaynames.insert(user.clone(), name.clone()),
+            None => self.displaynames.remove(user)
+        };
+        if self.nick_strategy != NickStrategy::Displayname || self.me.as_ref() == Some(user) {
+            return;
+        }
+        let old_mask = self.user_mask(user);
+        let old_nick = self.nick(user);
+        let new_nick = match displayname {
+            Some(ref name) => {
+                // Identity nicks belong to whoever they decode to
+                let nick = self.pick_nick(user, name);
+                if identity::is_reserved(&nick) {
+                    self.default_nick(user)
+                } else {
+                    nick
+                }
+            },
+            None => self.default_nick(user)
+        };
+        if new_nick == old_nick {
+            return;
+        }
+        if new_nick == self.default_nick(user) {
+            self.nicks.remove(user);
+        } else {
+            self.nicks.insert(user.clone(), new_nick.clone());
+        }
+        if self.has_irc_name() && !self.pending_sync && !self.is_pm && self.members.contains(user) {
+            let mut nick = irc::protocol::Message {
+                tags: irc::protocol::Tags::new(),
+                prefix: Some(old_mask),
+                command: irc::protocol::Command::Nick,
+                args: vec![],
+                suffix: Some(new_nick)
+            };
+            nick.set_server_time(ts);
+            callback(nick);
+        }
+    }
+
+    // Mentions at the start of a line get swapped between nicks and display
+    // names, since that's what each side's clients highlight on.
+    fn mention_to_matrix(&self, text: &str) -> String {
+        for sep in &[": ", ", "] {
+            if let Some(idx) = text.find(sep) {
+                if let Some(user) = self.user_from_nick(&text[..idx]) {
+                    if let Some(name) = self.displaynames.get(&user) {
+                        return format!("{}{}", name, &text[idx..]);
+                    }
+                }
+            }
+        }
+        text.to_string()
+    }
+
+    fn mention_from_matrix(&self, text: &str) -> String {
+        for (user, name) in &self.displaynames {
+            for sep in &[": ", ", "] {
+                if text.starts_with(&*format!("{}{}", name, sep)) && self.members.contains(user) {
+                    return format!("{}{}", self.nick(user), &text[name.len()..]);
+                }
+            }
+        }
+        text.to_string()
     }
 
     fn has_irc_name(&self) -> bool {
         self.irc_name != None
     }
 
-    fn handle_part<F>(&mut self, user: matrix::model::UserID, mut callback: &mut F)
+    fn handle_part<F>(&mut self, user: matrix::model::UserID, mut callback: &mut F, ts: u64)
             where F: FnMut(irc::protocol::Message) {
 
         let did_exist = self.members.remove(&user);
         if self.has_irc_name() && did_exist {
-            callback(irc::protocol::Message {
-                prefix: Some(Room::userid_to_irc(&user)),
+            let mut part = irc::protocol::Message {
+                tags: irc::protocol::Tags::new(),
+                prefix: Some(self.user_mask(&user)),
                 command: irc::protocol::Command::Part,
                 args: vec![self.irc_name.clone().unwrap()],
                 suffix: None
-            });
+            };
+            part.set_server_time(ts);
+            callback(part);
         }
+        self.nicks.remove(&user);
+        self.displaynames.remove(&user);
     }
 
-    fn handle_join<F>(&mut self, user: matrix::model::UserID, mut callback: &mut F)
+    fn handle_join<F>(&mut self, user: matrix::model::UserID, mut callback: &mut F, ts: u64)
             where F: FnMut(irc::protocol::Message) {
-        let uid = Room::userid_to_irc(&user);
+        let uid = self.user_mask(&user);
         let was_added = self.members.insert(user);
         if self.has_irc_name() && was_added {
-            callback(irc::protocol::Message {
+            let mut join = irc::protocol::Message {
+                tags: irc::protocol::Tags::new(),
                 prefix: Some(uid),
                 command: irc::protocol::Command::Join,
                 args: vec![self.irc_name.clone().unwrap()],
                 suffix: None
-            });
+            };
+            join.set_server_time(ts);
+            callback(join);
+        }
+    }
+
+    fn ban_mask(uid: &matrix::model::UserID) -> String {
+        format!("{}!*@{}", identity::ident(uid), uid.homeserver)
+    }
+
+    // Turns a nick!user@host mask back into a user, ignoring the user part.
+    // Wildcards can't be banned on matrix, so those don't match anyone.
+    fn user_from_mask(mask: &str) -> Option<matrix::model::UserID> {
+        match (mask.find('!'), mask.rfind('@')) {
+            (Some(bang), Some(at)) if bang < at => {
+                let (nick, host) = (&mask[..bang], &mask[at+1..]);
+                if nick.len() == 0 || host.len() == 0 || nick.contains("*") || host.contains("*") {
+                    None
+                } else {
+                    identity::user_id(nick, host)
+                }
+            },
+            _ => None
+        }
+    }
+
+    fn handle_kick<F>(&mut self, kicker: matrix::model::UserID, user: matrix::model::UserID, reason: Option<String>, mut callback: &mut F, ts: u64)
+            where F: FnMut(irc::protocol::Message) {
+        let did_exist = self.members.contains(&user);
+        if self.has_irc_name() && did_exist {
+            let mut kick = irc::protocol::Message {
+                tags: irc::protocol::Tags::new(),
+                prefix: Some(self.user_mask(&kicker)),
+                command: irc::protocol::Command::Kick,
+                args: vec![self.irc_name.clone().unwrap(), self.nick(&user)],
+                suffix: Some(reason.unwrap_or(self.nick(&kicker)))
+            };
+            kick.set_server_time(ts);
+            callback(kick);
+        }
+        self.members.remove(&user);
+        self.nicks.remove(&user);
+        self.displaynames.remove(&user);
+    }
+
+    // Matrix bans also take the user out of the room, so they get kicked
+    // on IRC too.
+    fn handle_ban<F>(&mut self, sender: matrix::model::UserID, user: matrix::model::UserID, reason: Option<String>, mut callback: &mut F, ts: u64)
+            where F: FnMut(irc::protocol::Message) {
+        let was_added = self.bans.insert(user.clone());
+        if self.has_irc_name() && was_added {
+            let mut mode = irc::protocol::Message {
+                tags: irc::protocol::Tags::new(),
+                prefix: Some(self.user_mask(&sender)),
+                command: irc::protocol::Command::Mode,
+                args: vec![self.irc_name.clone().unwrap(), "+b".to_string(), Room::ban_mask(&user)],
+                suffix: None
+            };
+            mode.set_server_time(ts);
+            callback(mode);
+        }
+        self.handle_kick(sender, user, reason, callback, ts);
+    }
+
+    fn handle_unban<F>(&mut self, sender: matrix::model::UserID, user: matrix::model::UserID, mut callback: &mut F, ts: u64)
+            where F: FnMut(irc::protocol::Message) {
+        let did_exist = self.bans.remove(&user);
+        if self.has_irc_name() && did_exist {
+            let mut mode = irc::protocol::Message {
+                tags: irc::protocol::Tags::new(),
+                prefix: Some(self.user_mask(&sender)),
+                command: irc::protocol::Command::Mode,
+                args: vec![self.irc_name.clone().unwrap(), "-b".to_string(), Room::ban_mask(&user)],
+                suffix: None
+            };
+            mode.set_server_time(ts);
+            callback(mode);
         }
     }
 
@@ -127,6 +379,15 @@ impl Room {
             pending_events: vec![],
             aliases: vec![],
             pending_sync: true,
+            prev_batch: None,
+            topic: None,
+            power_levels: None,
+            bans: BTreeSet::new(),
+            nick_strategy: NickStrategy::Localpart,
+            nicks: BTreeMap::new(),
+            displaynames: BTreeMap::new(),
+            me: None,
+            direct_with: None,
             irc_name: None,
             is_pm: false
         }
@@ -138,107 +399,364 @@ impl Room {
         self.pending_events.sort_by(|a, b|{
             a.0.cmp(&b.0)
         });
-        while let Some((age, evt)) = self.pending_events.pop() {
-            self.handle_with_alias(evt, callback, age);
+        while let Some((age, ts, evt)) = self.pending_events.pop() {
+            self.handle_with_alias(evt, callback, age, ts);
         }
     }
 
-    fn update_irc_name(&mut self, my_uid: &matrix::model::UserID) {
+    // Also used for rooms we aren't in, such as those in the public room
+    // directory, so this can only go off of aliases.
+    fn channel_name(id: &matrix::model::RoomID, canonical_alias: Option<&String>, aliases: &[String], homeserver: &str) -> String {
         // First check if we have a local alias that matches our homeserver
-        for a in &self.aliases {
-            if a.ends_with(&*format!(":{}", my_uid.homeserver)) {
-                self.irc_name = Some(a.clone());
-                break;
+        for a in aliases {
+            if a.ends_with(&*format!(":{}", homeserver)) {
+                return a.clone();
             }
         }
-        // If we didn't get one from above, try some other heuristics
-        if self.irc_name == None {
-            self.irc_name = match self.canonical_alias {
-                // No canonical alias set. See if we can grab some other alias.
-                None => {
-                    if self.aliases.len() == 0 {
-                        // A room with two people is probably a PM.
-                        // FIXME: Some other heuristics around private rooms, permissions, etc
-                        if self.members.len() == 2 {
-                            self.is_pm = true;
-                            Some(format!("{}", self.members.iter().nth(0).unwrap().nickname))
-                        } else {
-                            Some(format!("#{}:{}", self.id.id, self.id.homeserver))
-                        }
-                    } else {
-                        // First one is good as any I guess!
-                        Some(self.aliases[0].clone())
-                    }
-                },
-                // There's a canonical_alias set, so use that
-                Some(ref a) => Some(a.clone())
+        match canonical_alias {
+            // There's a canonical_alias set, so use that
+            Some(a) => a.clone(),
+            // No canonical alias set. See if we can grab some other alias.
+            None => match aliases.first() {
+                // First one is good as any I guess!
+                Some(a) => a.clone(),
+                None => format!("#{}:{}", id.id, id.homeserver)
+            }
+        }
+    }
+
+    fn update_irc_name(&mut self, my_uid: &matrix::model::UserID) {
+        let local_alias = format!(":{}", my_uid.homeserver);
+        let has_local_alias = self.aliases.iter().any(|a| a.ends_with(&*local_alias));
+        if let Some(user) = self.direct_with.clone() {
+            // DMs show up as a query with the other person
+            self.is_pm = true;
+            self.irc_name = Some(self.nick(&user));
+        } else if has_local_alias || self.irc_name == None {
+            // A local alias always wins, otherwise stick with the name we have
+            self.irc_name = Some(Room::channel_name(&self.id, self.canonical_alias.as_ref(), &self.aliases, &my_uid.homeserver));
+        }
+    }
+
+    // Replays old events inside a chathistory batch, so clients can tell
+    // them apart from what is happening now.
+    fn replay_history<F>(&mut self, history: Vec<matrix::events::Event>, batch: &str, mut callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        callback(irc::protocol::Message {
+            tags: irc::protocol::Tags::new(),
+            prefix: Some("pto".to_string()),
+            command: irc::protocol::Command::Batch,
+            args: vec![format!("+{}", batch), "chathistory".to_string(), self.irc_name.clone().unwrap()],
+            suffix: None
+        });
+        {
+            let mut batched = |mut msg: irc::protocol::Message| {
+                msg.tags.insert("batch".to_string(), batch.to_string());
+                callback(msg)
+            };
+            for evt in history {
+                if let matrix::events::EventData::Room(_, room_event) = evt.data {
+                    self.handle_with_alias(room_event, &mut batched, evt.age, evt.origin_server_ts);
+                }
+            }
+        }
+        callback(irc::protocol::Message {
+            tags: irc::protocol::Tags::new(),
+            prefix: Some("pto".to_string()),
+            command: irc::protocol::Command::Batch,
+            args: vec![format!("-{}", batch)],
+            suffix: None
+        });
+    }
+
+    // Anyone at or above OP_LEVEL is an op, and anyone else above the
+    // default is voiced.
+    fn user_mode(levels: &matrix::events::PowerLevels, user: &matrix::model::UserID) -> Option<char> {
+        let level = levels.user_level(user);
+        if level >= OP_LEVEL {
+            Some('o')
+        } else if level > levels.users_default() {
+            Some('v')
+        } else {
+            None
+        }
+    }
+
+    // Works out the level a +o/-o/+v/-v leaves the user at. Taking away a
+    // mode the user doesn't have leaves them alone.
+    fn level_with_mode(levels: &matrix::events::PowerLevels, user: &matrix::model::UserID, adding: bool, mode: char) -> i64 {
+        let level = levels.user_level(user);
+        let default = levels.users_default();
+        let current = Room::user_mode(levels, user);
+        match (adding, mode) {
+            (true, 'o') if level < OP_LEVEL => OP_LEVEL,
+            (true, 'v') if current == None => if VOICE_LEVEL > default { VOICE_LEVEL } else { default + 1 },
+            (false, 'o') if current == Some('o') => default,
+            (false, 'v') if current == Some('v') => default,
+            _ => level
+        }
+    }
+
+    // Whether our own level is enough for something, going by the power
+    // levels we know about. Without any, it's up to the homeserver.
+    fn has_level<F>(&self, required: F) -> bool
+            where F: Fn(&matrix::events::PowerLevels) -> i64 {
+        match (self.power_levels.as_ref(), self.me.as_ref()) {
+            (Some(levels), Some(me)) => levels.user_level(me) >= required(levels),
+            _ => true
+        }
+    }
+
+    fn user_prefix(&self, user: &matrix::model::UserID) -> &'static str {
+        match self.power_levels.as_ref().and_then(|l| Room::user_mode(l, user)) {
+            Some('o') => "@",
+            Some('v') => "+",
+            _ => ""
+        }
+    }
+
+    fn handle_power_levels<F>(&mut self, sender: matrix::model::UserID, levels: matrix::events::PowerLevels, mut callback: &mut F, ts: u64)
+            where F: FnMut(irc::protocol::Message) {
+        // The initial state doesn't need announcing, that's what NAMES is for.
+        // Levels for people who aren't in the room don't show up on IRC.
+        if self.has_irc_name() && !self.pending_sync && !self.is_pm {
+            let users: Vec<matrix::model::UserID> = self.members.iter().cloned().collect();
+            for user in users {
+                let old_mode = self.power_levels.as_ref().and_then(|l| Room::user_mode(l, &user));
+                let new_mode = Room::user_mode(&levels, &user);
+                if old_mode == new_mode {
+                    continue;
+                }
+                let mut changes = vec![];
+                if let Some(mode) = old_mode {
+                    changes.push(format!("-{}", mode));
+                }
+                if let Some(mode) = new_mode {
+                    changes.push(format!("+{}", mode));
+                }
+                for change in changes {
+                    let mut mode = irc::protocol::Message {
+                        tags: irc::protocol::Tags::new(),
+                        prefix: Some(self.user_mask(&sender)),
+                        command: irc::protocol::Command::Mode,
+                        args: vec![self.irc_name.clone().unwrap(), change, self.nick(&user)],
+                        suffix: None
+                    };
+                    mode.set_server_time(ts);
+                    callback(mode);
+                }
+            }
+        }
+        self.power_levels = Some(levels);
+    }
+
+    fn channel_symbol(&self) -> &'static str {
+        match self.join_rules {
+            Some(ref rules) if rules == "public" => "=",
+            _ => "*"
+        }
+    }
+
+    // The nicklist gets split up across as many 353s as it takes to keep
+    // each line under 512 bytes, and always ends with a 366.
+    fn names_reply(&self, my_nick: &str) -> Vec<irc::protocol::Message> {
+        let channel = self.irc_name.clone().unwrap();
+        let symbol = self.channel_symbol();
+        // ":pto 353 nick = #channel :" and the trailing CRLF
+        let overhead = ":pto 353 ".len() + my_nick.len() + 1 + symbol.len() + 1 + channel.len() + " :".len() + 2;
+        let mut lines: Vec<String> = vec![];
+        let mut line = String::new();
+        for u in &self.members {
+            let nick = format!("{}{}", self.user_prefix(u), self.nick(u));
+            if line.len() > 0 && overhead + line.len() + 1 + nick.len() > MAX_LINE_LENGTH {
+                lines.push(line);
+                line = String::new();
+            }
+            if line.len() > 0 {
+                line.push(' ');
             }
+            line.push_str(&nick);
+        }
+        if line.len() > 0 {
+            lines.push(line);
+        }
+
+        let mut ret: Vec<irc::protocol::Message> = vec![];
+        for names in lines {
+            ret.push(irc::protocol::Message {
+                tags: irc::protocol::Tags::new(),
+                prefix: Some("pto".to_string()),
+                command: irc::protocol::Command::Numeric(353),
+                args: vec![my_nick.to_string(), symbol.to_string(), channel.clone()],
+                suffix: Some(names)
+            });
+        }
+        ret.push(irc::protocol::Message {
+            tags: irc::protocol::Tags::new(),
+            prefix: Some("pto".to_string()),
+            command: irc::protocol::Command::Numeric(366),
+            args: vec![my_nick.to_string(), channel],
+            suffix: Some("End of /NAMES list.".to_string())
+        });
+        ret
+    }
+
+    // Rooms we just joined show up empty until the sync that carries their
+    // state comes in.
+    fn has_state(&self) -> bool {
+        self.members.len() > 0
+    }
+
+    fn send_join<F>(&self, my_uid: &matrix::model::UserID, callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        callback(irc::protocol::Message {
+            tags: irc::protocol::Tags::new(),
+            prefix: Some(self.user_mask(my_uid)),
+            command: irc::protocol::Command::Join,
+            args: vec![self.irc_name.clone().unwrap()],
+            suffix: None
+        });
+        // And then send the nicklist
+        for msg in self.names_reply(&self.nick(my_uid)) {
+            callback(msg);
+        }
+    }
+
+    // m.direct can change at any time, which turns channels into queries and
+    // back. Rooms that haven't been announced yet get sorted out by
+    // finish_sync.
+    fn set_direct_with<F>(&mut self, user: Option<matrix::model::UserID>, my_uid: &matrix::model::UserID, callback: &mut F)
+            where F: FnMut(irc::protocol::Message) {
+        if self.direct_with == user {
+            return;
+        }
+        let old_query = match self.direct_with {
+            Some(ref other) if self.is_pm => Some((self.user_mask(other), self.irc_name.clone())),
+            _ => None
+        };
+        self.direct_with = user;
+        if self.pending_sync || !self.has_state() {
+            return;
+        }
+        let old_channel = if self.is_pm { None } else { self.irc_name.clone() };
+        self.is_pm = false;
+        self.irc_name = None;
+        self.update_irc_name(my_uid);
+        // A query that's now with someone else gets renamed along with it,
+        // so the client doesn't keep writing into the old one
+        if let Some((mask, old_name)) = old_query {
+            if self.is_pm && self.irc_name != old_name {
+                callback(irc::protocol::Message {
+                    tags: irc::protocol::Tags::new(),
+                    prefix: Some(mask),
+                    command: irc::protocol::Command::Nick,
+                    args: vec![],
+                    suffix: self.irc_name.clone()
+                });
+            }
+        }
+        if let Some(channel) = old_channel {
+            callback(irc::protocol::Message {
+                tags: irc::protocol::Tags::new(),
+                prefix: Some(self.user_mask(my_uid)),
+                command: irc::protocol::Command::Part,
+                args: vec![channel],
+                suffix: Some("Moved to a private query".to_string())
+            });
+        }
+        if !self.is_pm {
+            self.send_join(my_uid, callback);
         }
     }
 
-    pub fn finish_sync<F>(&mut self, my_uid: &matrix::model::UserID, mut callback: &mut F)
+    pub fn finish_sync<F>(&mut self, my_uid: &matrix::model::UserID, backlog: Vec<matrix::events::Event>, batch: String, mut callback: &mut F)
             where F: FnMut(irc::protocol::Message) {
+        if !self.has_state() {
+            return;
+        }
         self.update_irc_name(my_uid);
         if self.pending_sync {
             if !self.is_pm {
                 // Send the initial join for the current user on this connection, now that we have an IRC friendly channel name
-                callback(irc::protocol::Message {
-                    prefix: Some(Room::userid_to_irc(my_uid)),
-                    command: irc::protocol::Command::Join,
-                    args: vec![self.irc_name.clone().unwrap()],
-                    suffix: None
-                });
-                // And then send the nicklist
-                let mut usernames: Vec<String> = vec![];
-                for u in &self.members {
-                    usernames.push(format!("{}", u.nickname));
-                }
-                callback(irc::protocol::Message {
-                    prefix: Some("pto".to_string()),
-                    command: irc::protocol::Command::Numeric(353),
-                    args: vec![my_uid.nickname.clone(), "@".to_string(), self.irc_name.clone().unwrap()],
-                    suffix: Some(usernames.join(" "))
-                });
+                self.send_join(my_uid, callback);
+            }
+            if backlog.len() > 0 {
+                self.replay_history(backlog, &batch, callback);
             }
             self.run_pending(callback);
             self.pending_sync = false;
         }
     }
 
-    fn handle_with_alias<F>(&mut self, evt: matrix::events::RoomEvent, mut callback: &mut F, age: u64)
+    fn handle_with_alias<F>(&mut self, evt: matrix::events::RoomEvent, mut callback: &mut F, age: u64, ts: u64)
             where F: FnMut(irc::protocol::Message) {
+        let mut callback = |mut msg: irc::protocol::Message| {
+            msg.set_server_time(ts);
+            callback(msg)
+        };
         if self.has_irc_name() {
             match evt {
-                matrix::events::RoomEvent::Membership(_, _) => (),
-                matrix::events::RoomEvent::Message(user, text) => {
-                    if self.is_pm {
-                        if self.irc_name == Some(user.nickname.clone()) {
-                            callback(irc::protocol::Message {
-                                prefix: Some(Room::userid_to_irc(&user)),
-                                command: irc::protocol::Command::Privmsg,
-                                args: vec![self.irc_name.clone().unwrap()],
-                                suffix: Some(text)
-                            });
+                matrix::events::RoomEvent::Membership(_) => (),
+                matrix::events::RoomEvent::Message(user, content) => {
+                    // Notices are how bots keep from setting each other off,
+                    // on both sides
+                    let is_notice = content.msgtype == matrix::events::MessageType::Notice;
+                    let is_emote = content.msgtype == matrix::events::MessageType::Emote;
+                    let body = match content.formatted_body {
+                        Some(ref html) => irc::formatting::html_to_irc(html),
+                        None => content.body.clone()
+                    };
+                    // IRC messages can't span lines, so each one goes out
+                    // on its own
+                    for line in body.split('\n').filter(|l| l.trim().len() > 0) {
+                        let command = if is_notice {
+                            irc::protocol::Command::Notice
+                        } else {
+                            irc::protocol::Command::Privmsg
+                        };
+                        let text = if is_emote {
+                            irc::ctcp::Ctcp::action(line)
+                        } else {
+                            line.to_string()
+                        };
+                        if self.is_pm {
+                            if self.me.as_ref() != Some(&user) {
+                                // Messages from the other side are to us
+                                let my_nick = match self.me {
+                                    Some(ref me) => self.nick(me),
+                                    None => self.irc_name.clone().unwrap()
+                                };
+                                callback(irc::protocol::Message {
+                                    tags: irc::protocol::Tags::new(),
+                                    prefix: Some(self.user_mask(&user)),
+                                    command: command,
+                                    args: vec![my_nick],
+                                    suffix: Some(text)
+                                });
+                            } else {
+                                callback(irc::protocol::Message {
+                                    tags: irc::protocol::Tags::new(),
+                                    prefix: None,
+                                    command: command,
+                                    args: vec![self.irc_name.clone().unwrap()],
+                                    suffix: Some(text)
+                                });
+                            }
                         } else {
                             callback(irc::protocol::Message {
-                                prefix: None,
-                                command: irc::protocol::Command::Privmsg,
+                                tags: irc::protocol::Tags::new(),
+                                prefix: Some(self.user_mask(&user)),
+                                command: command,
                                 args: vec![self.irc_name.clone().unwrap()],
-                                suffix: Some(text)
+                                suffix: Some(self.mention_from_matrix(&text))
                             });
                         }
-                    } else {
-                        callback(irc::protocol::Message {
-                            prefix: Some(Room::userid_to_irc(&user)),
-                            command: irc::protocol::Command::Privmsg,
-                            args: vec![self.irc_name.clone().unwrap()],
-                            suffix: Some(text)
-                        });
                     }
                 },
                 matrix::events::RoomEvent::Topic(user, topic) => {
                     callback(irc::protocol::Message {
-                        prefix: Some(Room::userid_to_irc(&user)),
+                        tags: irc::protocol::Tags::new(),
+                        prefix: Some(self.user_mask(&user)),
                         command: irc::protocol::Command::Topic,
                         args: vec![self.irc_name.clone().unwrap()],
                         suffix: Some(topic.clone())
@@ -249,12 +767,15 @@ impl Room {
                 }
             }
         } else {
-            self.pending_events.push((age, evt));
+            self.pending_events.push((age, ts, evt));
         }
     }
 
-    fn handle_event<F>(&mut self, evt: matrix::events::RoomEvent, mut callback: F, age: u64)
+    fn handle_event<F>(&mut self, evt: matrix::events::RoomEvent, mut callback: F, age: u64, ts: u64)
             where F: FnMut(irc::protocol::Message) {
+        if let matrix::events::RoomEvent::Topic(ref user, ref topic) = evt {
+            self.topic = Some((topic.clone(), user.clone(), ts));
+        }
         match evt {
             matrix::events::RoomEvent::CanonicalAlias(name) => {
                 self.canonical_alias = Some(name.clone());
@@ -264,15 +785,28 @@ impl Room {
             matrix::events::RoomEvent::Create => (),
             matrix::events::RoomEvent::Aliases(aliases) =>
                 self.aliases = aliases,
-            matrix::events::RoomEvent::PowerLevels => (),
+            matrix::events::RoomEvent::PowerLevels(sender, levels) =>
+                self.handle_power_levels(sender, levels, &mut callback, ts),
             matrix::events::RoomEvent::HistoryVisibility(_) => (),
             matrix::events::RoomEvent::Name(_, _) => (),
             matrix::events::RoomEvent::Avatar(_, _) => (),
-            matrix::events::RoomEvent::Membership(user, matrix::events::MembershipAction::Join) => {
-                self.handle_join(user, &mut callback);
+            matrix::events::RoomEvent::Membership(ref m) if m.is_unban() => {
+                self.handle_unban(m.sender.clone(), m.target.clone(), &mut callback, ts);
+            },
+            matrix::events::RoomEvent::Membership(ref m) if m.is_kick() => {
+                self.handle_kick(m.sender.clone(), m.target.clone(), m.reason.clone(), &mut callback, ts);
             },
-            matrix::events::RoomEvent::Membership(user, matrix::events::MembershipAction::Leave) => {
-                self.handle_part(user, &mut callback);
+            matrix::events::RoomEvent::Membership(m) => match m.action {
+                matrix::events::MembershipAction::Join => {
+                    self.update_nick(&m.target, m.displayname, &mut callback, ts);
+                    self.handle_join(m.target, &mut callback, ts);
+                },
+                matrix::events::MembershipAction::Leave =>
+                    self.handle_part(m.target, &mut callback, ts),
+                matrix::events::MembershipAction::Ban =>
+                    self.handle_ban(m.sender, m.target, m.reason, &mut callback, ts),
+                matrix::events::MembershipAction::Invite |
+                    matrix::events::MembershipAction::Other(_) => ()
             },
             matrix::events::RoomEvent::Unknown(unknown_type, json) => {
                 warn!("Unknown room event {}", unknown_type);
@@ -280,7 +814,7 @@ impl Room {
                     trace!("raw event: {:?}", json);
                 }
             }
-            _ => self.handle_with_alias(evt, &mut callback, age)
+            _ => self.handle_with_alias(evt, &mut callback, age, ts)
         };
     }
 }
@@ -289,7 +823,13 @@ impl Room {
 impl Bridge {
     fn room_from_matrix(&mut self, id: &matrix::model::RoomID) -> &mut Room {
         if !self.rooms.contains_key(id) {
-            self.rooms.insert(id.clone(), Room::new(id.clone()));
+            let mut room = Room::new(id.clone());
+            room.nick_strategy = self.config.nick_strategy();
+            room.me = self.matrix.uid.clone();
+            if let Some(ref me) = room.me {
+                room.nicks.insert(me.clone(), self.client.nickname());
+            }
+            self.rooms.insert(id.clone(), room);
         }
         match self.rooms.get_mut(id) {
             Some(room) => room,
@@ -312,13 +852,17 @@ impl Bridge {
         }
     }
 
-    pub fn new(client: irc::streams::Client, url: hyper::Url) -> Self {
+    pub fn new(client: irc::streams::Client, url: hyper::Url, config: Config) -> Self {
         Bridge {
             client: client,
             matrix: matrix::client::Client::new(url),
             rooms: HashMap::new(),
+            invites: HashMap::new(),
+            direct: matrix::events::DirectRooms::new(),
             seen_events: vec![],
-            last_token: String::new()
+            last_token: String::new(),
+            config: config,
+            next_batch: 0
         }
     }
 
@@ -328,10 +872,90 @@ impl Bridge {
         events.run(self).unwrap();
     }
 
+    fn is_history(evt: &matrix::events::Event) -> bool {
+        match evt.data {
+            matrix::events::EventData::Room(_, matrix::events::RoomEvent::Message(_, _)) => true,
+            matrix::events::EventData::Room(_, matrix::events::RoomEvent::Topic(_, _)) => true,
+            _ => false
+        }
+    }
+
+    // Pages backwards through a room's history from the given token, newest
+    // first, keeping the messages sent between after and before. Without an
+    // after, paging stops as soon as there are limit messages.
+    fn history(&mut self, room_id: &matrix::model::RoomID, from: String, limit: usize,
+               before: Option<u64>, after: Option<u64>) -> Vec<matrix::events::Event> {
+        let mut ret = vec![];
+        let mut token = from;
+        for _ in 0..HISTORY_MAX_PAGES {
+            let (events, end) = match self.matrix.messages(room_id, &token, HISTORY_PAGE_SIZE) {
+                Ok(page) => page,
+                Err(err) => {
+                    warn!("Could not fetch history for {}: {:?}", room_id, err);
+                    break;
+                }
+            };
+            let mut finished = events.len() == 0 || end.len() == 0;
+            for evt in events {
+                if let Some(ts) = after {
+                    if evt.origin_server_ts <= ts {
+                        finished = true;
+                        break;
+                    }
+                }
+                if let Some(ts) = before {
+                    if evt.origin_server_ts >= ts {
+                        continue;
+                    }
+                }
+                if Bridge::is_history(&evt) {
+                    ret.push(evt);
+                }
+            }
+            if finished || (after.is_none() && ret.len() >= limit) {
+                break;
+            }
+            token = end;
+        }
+        ret
+    }
+
+    fn direct_user(direct: &matrix::events::DirectRooms, room_id: &matrix::model::RoomID) -> Option<matrix::model::UserID> {
+        for (user, rooms) in direct {
+            if rooms.contains(room_id) {
+                return Some(user.clone());
+            }
+        }
+        None
+    }
+
     fn finish_sync<F>(&mut self, mut callback: &mut F, token: String)
             where F: FnMut(irc::protocol::Message) {
-        for (_, mut room) in &mut self.rooms {
-            room.finish_sync(&self.matrix.uid.as_ref().unwrap(), callback);
+        let uid = self.matrix.uid.clone().unwrap();
+        let mut backlogs: Vec<(matrix::model::RoomID, String, usize)> = vec![];
+        for (_, room) in &mut self.rooms {
+            if room.pending_sync && room.has_state() {
+                room.direct_with = Bridge::direct_user(&self.direct, &room.id);
+                room.update_irc_name(&uid);
+                let count = self.config.backlog(room.irc_name.as_ref().unwrap());
+                match room.prev_batch {
+                    Some(ref prev_batch) if count > 0 =>
+                        backlogs.push((room.id.clone(), prev_batch.clone(), count)),
+                    _ => ()
+                }
+            }
+        }
+        let mut backlog: HashMap<matrix::model::RoomID, Vec<matrix::events::Event>> = HashMap::new();
+        for (id, prev_batch, count) in backlogs {
+            let mut history = self.history(&id, prev_batch, count, None, None);
+            history.truncate(count);
+            history.reverse();
+            backlog.insert(id, history);
+        }
+        for (id, mut room) in &mut self.rooms {
+            self.next_batch += 1;
+            let history = backlog.remove(id).unwrap_or(vec![]);
+            room.finish_sync(&uid, history, format!("backlog{}", self.next_batch), callback);
         };
         self.last_token = token;
     }
@@ -350,7 +974,40 @@ impl Bridge {
                 };
                 match evt.data {
                     matrix::events::EventData::Room(room_id, room_event) => {
-                        self.room_from_matrix(&room_id).handle_event(room_event, append_msg, evt.age);
+                        // Joining from another client uses up the invite too
+                        self.forget_invite(&room_id);
+                        self.room_from_matrix(&room_id).handle_event(room_event, append_msg, evt.age, evt.origin_server_ts);
+                    },
+                    matrix::events::EventData::PrevBatch(room_id, token) => {
+                        let room = self.room_from_matrix(&room_id);
+                        if room.pending_sync {
+                            room.prev_batch = Some(token);
+                        }
+                    },
+                    matrix::events::EventData::Invite(room_id, inviter, alias) => {
+                        let homeserver = self.matrix.uid.clone().unwrap().homeserver;
+                        let channel = Room::channel_name(&room_id, alias.as_ref(), &[], &homeserver);
+                        if !self.invites.contains_key(&channel) && !self.rooms.contains_key(&room_id) {
+                            append_msg(irc::protocol::Message {
+                                tags: irc::protocol::Tags::new(),
+                                prefix: Some(identity::mask(&inviter, &homeserver)),
+                                command: irc::protocol::Command::Invite,
+                                args: vec![self.client.nickname(), channel.clone()],
+                                suffix: None
+                            });
+                            self.invites.insert(channel, room_id);
+                        }
+                    },
+                    matrix::events::EventData::Left(room_id) => {
+                        self.forget_invite(&room_id);
+                    },
+                    matrix::events::EventData::Direct(direct) => {
+                        self.direct = direct;
+                        let uid = self.matrix.uid.clone().unwrap();
+                        for (_, room) in self.rooms.iter_mut() {
+                            let user = Bridge::direct_user(&self.direct, &room.id);
+                            room.set_direct_with(user, &uid, &mut append_msg);
+                        }
                     },
                     matrix::events::EventData::Typing(_) => (),
                     matrix::events::EventData::EndOfSync(token) => self.finish_sync(&mut append_msg, token),
@@ -407,86 +1064,1387 @@ impl Bridge {
         })
     }
 
-    fn handle_client(&mut self, events: &mut EventLoop<Bridge>) {
-        loop {
-            match self.client.read_message() {
-                None => return,
-                Some(message) => {
-                    match message.command {
-                        Command::Pass => {
-                            self.client.auth.set_password(message.args[0].clone())
-                        }
-                        Command::Nick => {
-                            let nickname = match message.suffix {
-                                None => message.args[0].clone(),
-                                Some(n) => n
-                            };
-                            self.client.set_nickname(nickname)
-                        },
-                        Command::User => {
-                            self.client.auth.set_username(message.args[0].clone());
-                            let auth = self.client.auth.consume();
-                            match (auth.username, auth.password) {
-                                (Some(username), Some(password)) => {
-                                    self.matrix.login(&*username, &*password)
-                                        .and_then(|_| {
-                                            self.start_matrix(events.channel())
-                                        })
-                                        .and_then(|_| {
-                                            self.client.welcome("Welcome to Perpetually Talking Online!").unwrap();
-                                            debug!("Logged in a user");
-                                            Ok(())
-                                        }).expect("Could not login!");
-                                },
-                                (Some(_), None) => {
-                                    self.matrix.anon_login()
-                                        .and_then(|_| {
-                                            self.start_matrix(events.channel())
-                                        })
-                                        .and_then(|_| {
-                                            self.client.welcome("Welcome to Perpetually Talking Online!").unwrap();
-                                            debug!("Logged in a user");
-                                            Ok(())
-                                        }).expect("Could not login!");
-                                },
-                                _ => panic!("Username missing, and anonymous access isn't built yet.")
-                            };
-                        },
-                        Command::Join => {
-                            // FIXME: Send no such channel message
-                        },
-                        Command::Ping => {
-                            self.client.pong().expect("Could not send PONG");
-                        },
-                        Command::Quit => {
-                            // FIXME: Logout of matrix and exit thread
-                            return;
-                        },
-                        Command::Privmsg => {
-                            let room_id = match self.room_from_irc(&message.args[0]) {
-                                None => return (),
-                                Some(room) => room.id.clone()
-                            };
-                            let evt = {
-                                let id = self.matrix.uid.clone().unwrap();
-                                let message_text = if message.suffix == None {
-                                    message.args[1].clone()
-                                } else {
-                                    message.suffix.unwrap()
-                                };
-                                matrix::events::EventData::Room(
-                                    room_id,
-                                    matrix::events::RoomEvent::Message(
-                                        id, message_text))
-                            };
-                            self.seen_events.push(self.matrix.send(evt).expect("Could not send event"));
-                        },
-                        _ =>
-                            warn!("unhandled {:?}", message)
-                    }
-                }
+    fn register(&mut self, events: &mut EventLoop<Bridge>) {
+        if !self.client.ready_to_register() {
+            return;
+        }
+        debug!("Registering with capabilities {:?}", self.client.caps.enabled());
+        let auth = self.client.auth.consume();
+        let login = if self.matrix.uid.is_some() {
+            // Already logged in through SASL
+            Ok(())
+        } else {
+            match (auth.username, auth.password) {
+                (Some(username), Some(password)) =>
+                    self.matrix.login(&*username, &*password),
+                (Some(_), None) =>
+                    self.matrix.anon_login(),
+                _ => panic!("Username missing, and anonymous access isn't built yet.")
+            }
+        };
+        match login.and_then(|_| self.start_matrix(events.channel())) {
+            Ok(_) => {
+                self.client.welcome("Welcome to Perpetually Talking Online!").unwrap();
+                debug!("Logged in a user");
+            },
+            Err(err) => {
+                warn!("Could not login: {:?}", err);
+                self.client.send_numeric(464, vec![], "Could not login to matrix").unwrap();
             }
         }
     }
-}
 
+    // Once we're registered, the nick is the user's display name on matrix.
+    // Our own nick is pinned in every room, so it only ever changes here.
+    fn handle_nick(&mut self, message: &Message, events: &mut EventLoop<Bridge>) {
+        let nickname = match message.param(0) {
+            Some(n) => n,
+            None => {
+                self.client.send_numeric(431, vec![], "No nickname given").unwrap();
+                return;
+            }
+        };
+        if !identity::is_valid_nick(&nickname) {
+            self.client.send_numeric(432, vec![nickname], "Erroneous nickname").unwrap();
+            return;
+        }
+        if !self.client.is_registered() {
+            self.client.set_nickname(nickname);
+            self.register(events);
+            return;
+        }
+        let old_nick = self.client.nickname();
+        if nickname == old_nick {
+            return;
+        }
+        let uid = self.matrix.uid.clone().unwrap();
+        match self.user_from_irc(&nickname) {
+            Some(ref user) if *user != uid => {
+                self.client.send_numeric(433, vec![nickname], "Nickname is already in use").unwrap();
+                return;
+            },
+            _ => ()
+        }
+        match self.matrix.set_displayname(&nickname) {
+            Ok(_) => {
+                for (_, room) in self.rooms.iter_mut() {
+                    room.nicks.insert(uid.clone(), nickname.clone());
+                }
+                self.client.send(&Message {
+                    tags: irc::protocol::Tags::new(),
+                    prefix: Some(format!("{}!{}@{}", old_nick, identity::ident(&uid), uid.homeserver)),
+                    command: Command::Nick,
+                    args: vec![],
+                    suffix: Some(nickname.clone())
+                }).unwrap();
+                self.client.set_nickname(nickname);
+            },
+            // The nick itself was fine, so this isn't a 432. The client keeps
+            // its old nick.
+            Err(err) => {
+                warn!("Could not set display name to {}: {:?}", nickname, err);
+                self.client.send(&Message {
+                    tags: irc::protocol::Tags::new(),
+                    prefix: Some("pto".to_string()),
+                    command: Command::Fail,
+                    args: vec!["NICK".to_string(), "CANNOT_CHANGE_NICK".to_string(), nickname],
+                    suffix: Some(format!("Could not change your display name: {:?}", err))
+                }).unwrap();
+            }
+        }
+    }
+
+    fn sasl_success(&mut self) {
+        let uid = self.matrix.uid.clone().unwrap();
+        let mask = format!("{}!{}@{}", self.client.nickname(), identity::ident(&uid), uid.homeserver);
+        self.client.send_numeric(900, vec![mask, format!("{}", uid)],
+                                 &format!("You are now logged in as {}", uid)).unwrap();
+        self.client.send_numeric(903, vec![], "SASL authentication successful").unwrap();
+    }
+
+    fn sasl_login(&mut self, auth: irc::security::Auth) {
+        let username = auth.username.unwrap();
+        let password = auth.password.unwrap_or(String::new());
+        match self.matrix.login(&*username, &*password) {
+            Ok(_) => self.sasl_success(),
+            Err(err) => {
+                warn!("SASL login failed: {:?}", err);
+                self.client.send_numeric(904, vec![], "SASL authentication failed").unwrap();
+            }
+        }
+    }
+
+    fn sasl_external(&mut self, authzid: Option<String>) {
+        let login = self.client.certificate_fingerprint().and_then(|fingerprint| {
+            match irc::security::CertificateMap::load(Path::new("pto.certs")) {
+                Ok(map) => map.lookup(&fingerprint).cloned(),
+                Err(err) => {
+                    warn!("Could not load pto.certs: {:?}", err);
+                    None
+                }
+            }
+        });
+        match (login, authzid) {
+            (Some(ref login), Some(ref authzid)) if *authzid != login.user_id => {
+                warn!("Certificate for {} can't be used to login as {}", login.user_id, authzid);
+                self.client.send_numeric(904, vec![], "SASL authentication failed").unwrap();
+            },
+            (Some(login), _) => match self.matrix.token_login(&login.user_id, &login.access_token) {
+                Ok(_) => self.sasl_success(),
+                Err(err) => {
+                    warn!("Certificate login for {} failed: {:?}", login.user_id, err);
+                    self.client.send_numeric(904, vec![], "SASL authentication failed").unwrap();
+                }
+            },
+            (None, _) => {
+                self.client.send_numeric(904, vec![], "SASL authentication failed").unwrap();
+            }
+        }
+    }
+
+    fn handle_authenticate(&mut self, message: &Message) {
+        let param = match message.param(0) {
+            Some(p) => p,
+            None => return
+        };
+        // SASL is only on offer to clients that asked for it
+        if !self.client.has_cap("sasl") {
+            self.client.send_numeric(904, vec![], "SASL authentication failed").unwrap();
+            return;
+        }
+        if self.matrix.uid.is_some() {
+            self.client.send_numeric(907, vec![], "You have already authenticated using SASL").unwrap();
+            return;
+        }
+        if !self.client.auth.in_sasl() {
+            match irc::security::SaslMechanism::from_str(&param) {
+                Some(mechanism) => {
+                    self.client.auth.begin_sasl(mechanism);
+                    self.client.send(&Message {
+                        tags: irc::protocol::Tags::new(),
+                        prefix: None,
+                        command: Command::Authenticate,
+                        args: vec!["+".to_string()],
+                        suffix: None
+                    }).unwrap();
+                },
+                None => {
+                    self.client.send_numeric(908, vec![irc::security::SaslMechanism::available().to_string()],
+                                             "are available SASL mechanisms").unwrap();
+                    self.client.send_numeric(904, vec![], "SASL authentication failed").unwrap();
+                }
+            }
+            return;
+        }
+        match self.client.auth.sasl_chunk(&param) {
+            irc::security::SaslStep::Continue => (),
+            irc::security::SaslStep::Done(auth) =>
+                self.sasl_login(auth),
+            irc::security::SaslStep::External(authzid) =>
+                self.sasl_external(authzid),
+            irc::security::SaslStep::Aborted => {
+                self.client.send_numeric(906, vec![], "SASL authentication aborted").unwrap();
+            },
+            irc::security::SaslStep::TooLong => {
+                self.client.send_numeric(905, vec![], "SASL message too long").unwrap();
+            },
+            irc::security::SaslStep::Failed => {
+                self.client.send_numeric(904, vec![], "SASL authentication failed").unwrap();
+            }
+        }
+    }
+
+    fn chathistory_fail(&mut self, code: &str, mut args: Vec<String>, text: &str) {
+        args.insert(0, "CHATHISTORY".to_string());
+        args.insert(1, code.to_string());
+        self.client.send(&Message {
+            tags: irc::protocol::Tags::new(),
+            prefix: Some("pto".to_string()),
+            command: Command::Fail,
+            args: args,
+            suffix: Some(text.to_string())
+        }).unwrap();
+    }
+
+    fn handle_chathistory(&mut self, message: &Message) {
+        let (subcommand, target, reference, limit) = match (message.param(0), message.param(1), message.param(2), message.param(3)) {
+            (Some(s), Some(t), Some(r), Some(l)) => (s.to_uppercase(), t, r, l),
+            _ => {
+                self.chathistory_fail("NEED_MORE_PARAMS", vec![], "Missing parameters");
+                return;
+            }
+        };
+        let limit = match limit.parse::<usize>() {
+            Ok(l) if l <= CHATHISTORY_MAX_LIMIT => l,
+            Ok(_) => CHATHISTORY_MAX_LIMIT,
+            Err(_) => {
+                self.chathistory_fail("INVALID_PARAMS", vec![subcommand], "Invalid limit");
+                return;
+            }
+        };
+        // Matrix can't look up events by ID through the client API, so only
+        // timestamps are usable as references.
+        let timestamp = if reference == "*" {
+            None
+        } else if reference.starts_with("timestamp=") {
+            irc::util::parse_timestamp(&reference["timestamp=".len()..])
+        } else {
+            None
+        };
+        let (before, after, keep_oldest) = match (&*subcommand, &*reference, timestamp) {
+            ("LATEST", "*", _) => (None, None, false),
+            ("LATEST", _, Some(ts)) => (None, Some(ts), false),
+            ("BEFORE", _, Some(ts)) => (Some(ts), None, false),
+            ("AFTER", _, Some(ts)) => (None, Some(ts), true),
+            _ => {
+                self.chathistory_fail("INVALID_PARAMS", vec![subcommand.clone()], "Unsupported subcommand or message reference");
+                return;
+            }
+        };
+        let room_id = match self.room_from_irc(&target) {
+            Some(room) => room.id.clone(),
+            None => {
+                self.chathistory_fail("INVALID_TARGET", vec![subcommand.clone(), target.clone()], "No such channel");
+                return;
+            }
+        };
+        let token = self.last_token.clone();
+        let mut history = self.history(&room_id, token, limit, before, after);
+        if keep_oldest {
+            let excess = history.len().saturating_sub(limit);
+            history.drain(..excess);
+        } else {
+            history.truncate(limit);
+        }
+        history.reverse();
+
+        self.next_batch += 1;
+        let batch = format!("history{}", self.next_batch);
+        let mut messages: Vec<Message> = vec![];
+        {
+            let mut append_msg = |msg: Message| {
+                messages.push(msg);
+            };
+            self.room_from_matrix(&room_id).replay_history(history, &batch, &mut append_msg);
+        }
+        for msg in messages {
+            self.client.send(&msg).unwrap();
+        }
+    }
+
+    fn join_channel(&mut self, channel: &str) {
+        let homeserver = self.matrix.uid.clone().unwrap().homeserver;
+        // Plain channel names are aliases on our own homeserver, and
+        // invites get joined by ID since they might not have an alias at all
+        let target = if let Some(room_id) = self.invites.get(channel).cloned() {
+            format!("{}", room_id)
+        } else if channel.starts_with("!") || channel.contains(":") {
+            channel.to_string()
+        } else {
+            format!("{}:{}", channel, homeserver)
+        };
+        if !channel.starts_with("#") && !channel.starts_with("!") {
+            self.client.send_numeric(403, vec![channel.to_string()], "No such channel").unwrap();
+            return;
+        }
+        if self.room_from_irc(&channel.to_string()).is_some() || self.room_from_irc(&target).is_some() {
+            return;
+        }
+        match self.matrix.join(&target) {
+            Ok(room_id) => {
+                // The JOIN and nicklist go out once the room's state shows
+                // up in the next sync.
+                debug!("Joined {} as {}", target, room_id);
+                self.forget_invite(&room_id);
+                self.room_from_matrix(&room_id);
+            },
+            Err(err) => {
+                warn!("Could not join {}: {:?}", target, err);
+                let (numeric, text) = match err {
+                    matrix::client::ClientError::Matrix(_, ref errcode, ref error) if errcode == "M_FORBIDDEN" => {
+                        if error.contains("banned") {
+                            (474, "Cannot join channel (+b)")
+                        } else {
+                            (473, "Cannot join channel (+i)")
+                        }
+                    },
+                    _ => (403, "No such channel")
+                };
+                self.client.send_numeric(numeric, vec![channel.to_string()], text).unwrap();
+            }
+        }
+    }
+
+    fn handle_join(&mut self, message: &Message) {
+        if !self.client.is_registered() {
+            self.client.send_numeric(451, vec![], "You have not registered").unwrap();
+            return;
+        }
+        match message.param(0) {
+            Some(channels) => {
+                for channel in channels.split(",") {
+                    self.join_channel(channel);
+                }
+            },
+            None => {
+                self.client.send_numeric(461, vec!["JOIN".to_string()], "Not enough parameters").unwrap();
+            }
+        }
+    }
+
+    fn forget_invite(&mut self, room_id: &matrix::model::RoomID) {
+        let channels: Vec<String> = self.invites.iter()
+            .filter(|&(_, id)| id == room_id)
+            .map(|(channel, _)| channel.clone())
+            .collect();
+        for channel in channels {
+            self.invites.remove(&channel);
+        }
+    }
+
+    fn part_channel(&mut self, channel: &str, reason: Option<String>) {
+        let room_id = match self.room_from_irc(&channel.to_string()) {
+            Some(room) => room.id.clone(),
+            None => {
+                self.client.send_numeric(442, vec![channel.to_string()], "You're not on that channel").unwrap();
+                return;
+            }
+        };
+        // "/part #chan -forget" also forgets the room, so it vanishes from
+        // the room list in other matrix clients.
+        let (forget, reason) = match reason {
+            Some(ref r) if r == "-forget" => (true, None),
+            Some(ref r) if r.starts_with("-forget ") => (true, Some(r["-forget ".len()..].to_string())),
+            r => (false, r)
+        };
+        let left = self.matrix.leave(&room_id, reason.as_ref().map(|r| &**r)).and_then(|_| {
+            if forget {
+                self.matrix.forget(&room_id)
+            } else {
+                Ok(())
+            }
+        });
+        match left {
+            Ok(_) => {
+                self.rooms.remove(&room_id);
+                self.forget_invite(&room_id);
+                let uid = self.matrix.uid.clone().unwrap();
+                self.client.send(&Message {
+                    tags: irc::protocol::Tags::new(),
+                    prefix: Some(format!("{}!{}@{}", self.client.nickname(), identity::ident(&uid), uid.homeserver)),
+                    command: Command::Part,
+                    args: vec![channel.to_string()],
+                    suffix: reason
+                }).unwrap();
+            },
+            Err(err) => {
+                warn!("Could not leave {}: {:?}", channel, err);
+                self.client.send_numeric(442, vec![channel.to_string()], "Could not leave channel").unwrap();
+            }
+        }
+    }
+
+    fn handle_part(&mut self, message: &Message) {
+        let reason = message.param(1);
+        match message.param(0) {
+            Some(channels) => {
+                for channel in channels.split(",") {
+                    self.part_channel(channel, reason.clone());
+                }
+            },
+            None => {
+                self.client.send_numeric(461, vec!["PART".to_string()], "Not enough parameters").unwrap();
+            }
+        }
+    }
+
+    fn handle_topic(&mut self, message: &Message) {
+        let channel = match message.param(0) {
+            Some(c) => c,
+            None => {
+                self.client.send_numeric(461, vec!["TOPIC".to_string()], "Not enough parameters").unwrap();
+                return;
+            }
+        };
+        let (room_id, topic) = match self.room_from_irc(&channel) {
+            Some(room) => (room.id.clone(), room.topic.as_ref().map(|&(ref text, ref setter, ts)| (text.clone(), room.user_mask(setter), ts))),
+            None => {
+                self.client.send_numeric(442, vec![channel], "You're not on that channel").unwrap();
+                return;
+            }
+        };
+        match message.param(1) {
+            None => match topic {
+                Some((ref text, _, _)) if text.len() == 0 => {
+                    self.client.send_numeric(331, vec![channel], "No topic is set").unwrap();
+                },
+                Some((text, setter, ts)) => {
+                    self.client.send_numeric(332, vec![channel.clone()], &text).unwrap();
+                    self.client.send_numeric(333, vec![channel, setter], &format!("{}", ts / 1000)).unwrap();
+                },
+                None => {
+                    self.client.send_numeric(331, vec![channel], "No topic is set").unwrap();
+                }
+            },
+            Some(text) => {
+                // The new topic gets relayed back to the client when it
+                // comes down the sync.
+                let evt = matrix::events::EventData::Room(
+                    room_id,
+                    matrix::events::RoomEvent::Topic(self.matrix.uid.clone().unwrap(), text));
+                match self.matrix.set_state(evt, "") {
+                    Ok(_) => (),
+                    Err(ref err) if err.errcode() == Some("M_FORBIDDEN") => {
+                        self.client.send_numeric(482, vec![channel], "You're not channel operator").unwrap();
+                    },
+                    Err(err) => warn!("Could not set topic on {}: {:?}", channel, err)
+                }
+            }
+        }
+    }
+
+    fn handle_names(&mut self, message: &Message) {
+        let nickname = self.client.nickname();
+        let channels = match message.param(0) {
+            Some(c) => c,
+            None => {
+                self.client.send_numeric(366, vec!["*".to_string()], "End of /NAMES list.").unwrap();
+                return;
+            }
+        };
+        for channel in channels.split(",") {
+            let reply = match self.room_from_irc(&channel.to_string()) {
+                Some(ref room) if room.has_irc_name() => room.names_reply(&nickname),
+                _ => vec![]
+            };
+            if reply.len() == 0 {
+                self.client.send_numeric(366, vec![channel.to_string()], "End of /NAMES list.").unwrap();
+            }
+            for msg in reply {
+                self.client.send(&msg).unwrap();
+            }
+        }
+    }
+
+    // Ops and voices get changed by sending a new m.room.power_levels, which
+    // comes back down the sync as MODE changes.
+    fn handle_mode(&mut self, message: &Message) {
+        let target = match message.param(0) {
+            Some(t) => t,
+            None => {
+                self.client.send_numeric(461, vec!["MODE".to_string()], "Not enough parameters").unwrap();
+                return;
+            }
+        };
+        if !target.starts_with("#") {
+            // We don't do any user modes
+            if target == self.client.nickname() {
+                self.client.send_numeric(221, vec!["+".to_string()], "").unwrap();
+            } else {
+                self.client.send_numeric(502, vec![], "Can't change mode for other users").unwrap();
+            }
+            return;
+        }
+        let (room_id, bans, levels, can_op, can_ban) = match self.room_from_irc(&target) {
+            Some(room) => {
+                let levels = match room.power_levels {
+                    Some(ref l) => l.clone(),
+                    None => matrix::events::PowerLevels::new()
+                };
+                (room.id.clone(), room.bans.clone(), levels,
+                 room.has_level(|l| l.state_default()), room.has_level(|l| l.ban()))
+            },
+            None => {
+                self.client.send_numeric(403, vec![target], "No such channel").unwrap();
+                return;
+            }
+        };
+        let modes = match message.param(1) {
+            Some(m) => m,
+            None => {
+                self.client.send_numeric(324, vec![target, "+".to_string()], "").unwrap();
+                return;
+            }
+        };
+
+        let mut new_levels = levels.clone();
+        let mut changed = false;
+        let mut adding = true;
+        let mut next_arg = 2;
+        for mode in modes.chars() {
+            match mode {
+                '+' => adding = true,
+                '-' => adding = false,
+                'o' | 'v' => {
+                    let nick = match message.param(next_arg) {
+                        Some(n) => n,
+                        None => {
+                            self.client.send_numeric(461, vec!["MODE".to_string()], "Not enough parameters").unwrap();
+                            return;
+                        }
+                    };
+                    next_arg += 1;
+                    if !can_op {
+                        self.client.send_numeric(482, vec![target.clone()], "You're not channel operator").unwrap();
+                        continue;
+                    }
+                    let user = match self.room_from_irc(&target).and_then(|room| room.user_from_nick(&nick)) {
+                        Some(u) => u,
+                        None => {
+                            self.client.send_numeric(441, vec![nick.clone(), target.clone()], "They aren't on that channel").unwrap();
+                            continue;
+                        }
+                    };
+                    let level = Room::level_with_mode(&new_levels, &user, adding, mode);
+                    if level != new_levels.user_level(&user) {
+                        new_levels.set_user_level(&user, level);
+                        changed = true;
+                    }
+                },
+                'b' => {
+                    let mask = match message.param(next_arg) {
+                        Some(m) => m,
+                        None => {
+                            // No mask means they want the ban list
+                            for user in &bans {
+                                self.client.send_numeric(367, vec![target.clone(), Room::ban_mask(user)], "").unwrap();
+                            }
+                            self.client.send_numeric(368, vec![target.clone()], "End of channel ban list").unwrap();
+                            continue;
+                        }
+                    };
+                    next_arg += 1;
+                    if !can_ban {
+                        self.client.send_numeric(482, vec![target.clone()], "You're not channel operator").unwrap();
+                        continue;
+                    }
+                    let user = match Room::user_from_mask(&mask) {
+                        Some(u) => Some(u),
+                        None => match self.room_from_irc(&target).and_then(|room| room.user_from_nick(&mask)) {
+                            Some(u) => Some(u),
+                            None => bans.iter().find(|u| identity::ident(u) == mask).cloned()
+                        }
+                    };
+                    let user = match user {
+                        Some(u) => u,
+                        None => {
+                            self.client.send_numeric(401, vec![mask.clone()], "No such nick/channel").unwrap();
+                            continue;
+                        }
+                    };
+                    let result = if adding {
+                        self.matrix.ban(&room_id, &user, None)
+                    } else {
+                        self.matrix.unban(&room_id, &user)
+                    };
+                    match result {
+                        Ok(_) => (),
+                        Err(ref err) if err.errcode() == Some("M_FORBIDDEN") => {
+                            self.client.send_numeric(482, vec![target.clone()], "You're not channel operator").unwrap();
+                        },
+                        Err(err) => warn!("Could not change bans on {}: {:?}", target, err)
+                    }
+                },
+                _ => {
+                    self.client.send_numeric(472, vec![mode.to_string()], "is unknown mode char to me").unwrap();
+                }
+            }
+        }
+        if !changed {
+            return;
+        }
+
+        let evt = matrix::events::EventData::Room(
+            room_id,
+            matrix::events::RoomEvent::PowerLevels(self.matrix.uid.clone().unwrap(), new_levels));
+        match self.matrix.set_state(evt, "") {
+            Ok(_) => (),
+            Err(ref err) if err.errcode() == Some("M_FORBIDDEN") => {
+                self.client.send_numeric(482, vec![target], "You're not channel operator").unwrap();
+            },
+            Err(err) => warn!("Could not change modes on {}: {:?}", target, err)
+        }
+    }
+
+    fn handle_kick(&mut self, message: &Message) {
+        let (channel, nicks) = match (message.param(0), message.param(1)) {
+            (Some(c), Some(n)) => (c, n),
+            _ => {
+                self.client.send_numeric(461, vec!["KICK".to_string()], "Not enough parameters").unwrap();
+                return;
+            }
+        };
+        let reason = message.param(2);
+        let (room_id, can_kick) = match self.room_from_irc(&channel) {
+            Some(room) => (room.id.clone(), room.has_level(|l| l.kick())),
+            None => {
+                self.client.send_numeric(403, vec![channel], "No such channel").unwrap();
+                return;
+            }
+        };
+        if !can_kick {
+            self.client.send_numeric(482, vec![channel], "You're not channel operator").unwrap();
+            return;
+        }
+        for nick in nicks.split(",") {
+            let user = match self.room_from_irc(&channel).and_then(|room| room.user_from_nick(nick)) {
+                Some(u) => u,
+                None => {
+                    self.client.send_numeric(441, vec![nick.to_string(), channel.clone()], "They aren't on that channel").unwrap();
+                    continue;
+                }
+            };
+            // The KICK gets relayed back to the client when it comes down
+            // the sync.
+            match self.matrix.kick(&room_id, &user, reason.as_ref().map(|r| &**r)) {
+                Ok(_) => (),
+                Err(ref err) if err.errcode() == Some("M_FORBIDDEN") => {
+                    self.client.send_numeric(482, vec![channel.clone()], "You're not channel operator").unwrap();
+                },
+                Err(err) => warn!("Could not kick {} from {}: {:?}", nick, channel, err)
+            }
+        }
+    }
+
+    fn handle_message(&mut self, message: Message) {
+        if !self.client.is_registered() {
+            self.client.send_numeric(451, vec![], "You have not registered").unwrap();
+            return;
+        }
+        let mut msgtype = match message.command {
+            Command::Notice => matrix::events::MessageType::Notice,
+            _ => matrix::events::MessageType::Text
+        };
+        let mut message_text = if message.suffix == None {
+            message.args[1].clone()
+        } else {
+            message.suffix.unwrap()
+        };
+        if let Some(ctcp) = irc::ctcp::Ctcp::parse(&message_text) {
+            match (msgtype, &*ctcp.command) {
+                (matrix::events::MessageType::Text, "ACTION") => {
+                    msgtype = matrix::events::MessageType::Emote;
+                    message_text = ctcp.params.clone().unwrap_or(String::new());
+                },
+                (matrix::events::MessageType::Text, _) => {
+                    self.ctcp_reply(&message.args[0], &ctcp);
+                    return;
+                },
+                // CTCP replies have nowhere to go
+                _ => {
+                    debug!("Dropping CTCP reply {:?}", ctcp);
+                    return;
+                }
+            }
+        }
+        let in_room = match self.room_from_irc(&message.args[0]) {
+            None => None,
+            Some(room) => Some((room.id.clone(), room.mention_to_matrix(&message_text)))
+        };
+        let (room_id, message_text) = match in_room {
+            Some(found) => found,
+            None => match self.direct_room(&message.args[0]) {
+                None => return,
+                Some(room_id) => (room_id, message_text)
+            }
+        };
+        // Matrix gets the formatting as HTML, with a plain body for clients
+        // that don't render it
+        let mut content = matrix::events::MessageContent::new(msgtype, irc::formatting::strip_codes(&message_text));
+        content.formatted_body = irc::formatting::irc_to_html(&message_text);
+        let evt = {
+            let id = self.matrix.uid.clone().unwrap();
+            matrix::events::EventData::Room(
+                room_id,
+                matrix::events::RoomEvent::Message(id, content))
+        };
+        self.seen_events.push(self.matrix.send(evt).expect("Could not send event"));
+    }
+
+    // Answers CTCP queries to anyone on their behalf, and drops the rest so
+    // none of it ends up on matrix.
+    fn ctcp_reply(&mut self, target: &str, ctcp: &irc::ctcp::Ctcp) {
+        let reply = match ctcp.reply() {
+            Some(r) => r,
+            None => {
+                debug!("Dropping unsupported CTCP {:?} to {}", ctcp, target);
+                return;
+            }
+        };
+        let prefix = if target.starts_with("#") {
+            "pto".to_string()
+        } else {
+            let homeserver = match self.matrix.uid {
+                Some(ref uid) => uid.homeserver.clone(),
+                // Not logged in yet, so there's nobody to answer for
+                None => return
+            };
+            match self.decode_nick(target) {
+                Some(user) => identity::mask(&user, &homeserver),
+                None => target.to_string()
+            }
+        };
+        self.client.send(&Message {
+            tags: irc::protocol::Tags::new(),
+            prefix: Some(prefix),
+            command: Command::Notice,
+            args: vec![self.client.nickname()],
+            suffix: Some(reply.to_string())
+        }).unwrap();
+    }
+
+    // Finds the DM room for someone we aren't already talking to, starting a
+    // new one if there isn't one yet.
+    fn direct_room(&mut self, nick: &str) -> Option<matrix::model::RoomID> {
+        let user = match self.decode_nick(nick) {
+            Some(u) if !nick.starts_with("#") => u,
+            _ => {
+                self.client.send_numeric(401, vec![nick.to_string()], "No such nick/channel").unwrap();
+                return None;
+            }
+        };
+        if let Some(rooms) = self.direct.get(&user) {
+            for room_id in rooms {
+                if self.rooms.contains_key(room_id) {
+                    return Some(room_id.clone());
+                }
+            }
+        }
+        match self.matrix.create_direct_room(&user) {
+            Ok(room_id) => {
+                debug!("Started a DM with {} in {}", user, room_id);
+                self.direct.entry(user).or_insert(vec![]).push(room_id.clone());
+                if let Err(err) = self.matrix.set_direct_rooms(&self.direct) {
+                    warn!("Could not update m.direct: {:?}", err);
+                }
+                self.room_from_matrix(&room_id);
+                Some(room_id)
+            },
+            Err(err) => {
+                warn!("Could not start a DM with {}: {:?}", user, err);
+                self.client.send_numeric(401, vec![nick.to_string()], "No such nick/channel").unwrap();
+                None
+            }
+        }
+    }
+
+    // Anyone we share a room with can be invited by nick, and anyone else by
+    // their full matrix ID.
+    fn handle_invite(&mut self, message: &Message) {
+        if !self.client.is_registered() {
+            self.client.send_numeric(451, vec![], "You have not registered").unwrap();
+            return;
+        }
+        let (nick, channel) = match (message.param(0), message.param(1)) {
+            (Some(n), Some(c)) => (n, c),
+            _ => {
+                self.client.send_numeric(461, vec!["INVITE".to_string()], "Not enough parameters").unwrap();
+                return;
+            }
+        };
+        let user = if nick.starts_with("@") && nick.contains(":") {
+            Some(matrix::model::UserID::from_str(&nick))
+        } else {
+            self.decode_nick(&nick)
+        };
+        let user = match user {
+            Some(u) => u,
+            None => {
+                self.client.send_numeric(401, vec![nick], "No such nick/channel").unwrap();
+                return;
+            }
+        };
+        let (room_id, is_member, can_invite) = match self.room_from_irc(&channel) {
+            Some(room) => (room.id.clone(), room.members.contains(&user), room.has_level(|l| l.invite())),
+            None => {
+                self.client.send_numeric(442, vec![channel], "You're not on that channel").unwrap();
+                return;
+            }
+        };
+        if is_member {
+            self.client.send_numeric(443, vec![nick, channel], "is already on channel").unwrap();
+            return;
+        }
+        if !can_invite {
+            self.client.send_numeric(482, vec![channel], "You're not channel operator").unwrap();
+            return;
+        }
+        match self.matrix.invite(&room_id, &user) {
+            Ok(_) => {
+                self.client.send_numeric(341, vec![nick], &channel).unwrap();
+            },
+            Err(ref err) if err.errcode() == Some("M_FORBIDDEN") => {
+                self.client.send_numeric(482, vec![channel], "You're not channel operator").unwrap();
+            },
+            Err(err) => warn!("Could not invite {} to {}: {:?}", user, channel, err)
+        }
+    }
+
+    // Nicks of people we can't see still decode to someone, who may or may
+    // not exist.
+    fn decode_nick(&self, nick: &str) -> Option<matrix::model::UserID> {
+        match self.user_from_irc(nick) {
+            Some(u) => Some(u),
+            None => match self.matrix.uid {
+                Some(ref uid) => identity::user_id(nick, &uid.homeserver),
+                None => None
+            }
+        }
+    }
+
+    fn user_from_irc(&self, nick: &str) -> Option<matrix::model::UserID> {
+        for (_, room) in self.rooms.iter() {
+            if let Some(u) = room.user_from_nick(nick) {
+                return Some(u);
+            }
+        }
+        None
+    }
+
+    // Channels the user shares with us, leaving out PMs.
+    fn shared_channels(&self, user: &matrix::model::UserID) -> Vec<String> {
+        let mut ret = vec![];
+        for (_, room) in self.rooms.iter() {
+            if let Some(ref name) = room.irc_name {
+                if !room.is_pm && room.members.contains(user) {
+                    ret.push(name.clone());
+                }
+            }
+        }
+        ret.sort();
+        ret
+    }
+
+    fn who_reply(&mut self, channel: &str, user: &matrix::model::UserID) {
+        let homeserver = self.matrix.uid.clone().unwrap().homeserver;
+        let (nick, prefix) = match self.room_from_irc(&channel.to_string()) {
+            Some(room) => (room.nick(user), room.user_prefix(user)),
+            None => (identity::nick(user, &homeserver), "")
+        };
+        self.client.send_numeric(352, vec![channel.to_string(), identity::ident(user), user.homeserver.clone(),
+                                           "pto".to_string(), nick, format!("H{}", prefix)],
+                                 &format!("0 {}", user)).unwrap();
+    }
+
+    fn handle_who(&mut self, message: &Message) {
+        if !self.client.is_registered() {
+            self.client.send_numeric(451, vec![], "You have not registered").unwrap();
+            return;
+        }
+        let mask = match message.param(0) {
+            Some(m) => m,
+            None => {
+                self.client.send_numeric(315, vec!["*".to_string()], "End of /WHO list.").unwrap();
+                return;
+            }
+        };
+        let members: Option<Vec<matrix::model::UserID>> = match self.room_from_irc(&mask) {
+            Some(room) => Some(room.members.iter().cloned().collect()),
+            None => None
+        };
+        match members {
+            Some(members) => {
+                for user in members {
+                    self.who_reply(&mask, &user);
+                }
+            },
+            None => {
+                if let Some(user) = self.user_from_irc(&mask) {
+                    self.who_reply("*", &user);
+                }
+            }
+        }
+        self.client.send_numeric(315, vec![mask], "End of /WHO list.").unwrap();
+    }
+
+    fn handle_whois(&mut self, message: &Message) {
+        if !self.client.is_registered() {
+            self.client.send_numeric(451, vec![], "You have not registered").unwrap();
+            return;
+        }
+        // WHOIS can be aimed at a specific server, which comes first
+        let nick = match (message.param(0), message.param(1)) {
+            (_, Some(n)) => n,
+            (Some(n), None) => n,
+            (None, None) => {
+                self.client.send_numeric(431, vec![], "No nickname given").unwrap();
+                return;
+            }
+        };
+        let (user, visible) = match self.user_from_irc(&nick) {
+            Some(u) => (u, true),
+            None => match self.decode_nick(&nick) {
+                Some(u) => (u, false),
+                None => {
+                    self.client.send_numeric(401, vec![nick.clone()], "No such nick/channel").unwrap();
+                    self.client.send_numeric(318, vec![nick], "End of /WHOIS list.").unwrap();
+                    return;
+                }
+            }
+        };
+        let profile = match self.matrix.profile(&user) {
+            Ok(p) => p,
+            // Someone we don't share a room with only exists if they have a
+            // profile
+            Err(_) if !visible => {
+                self.client.send_numeric(401, vec![nick.clone()], "No such nick/channel").unwrap();
+                self.client.send_numeric(318, vec![nick], "End of /WHOIS list.").unwrap();
+                return;
+            },
+            Err(err) => {
+                warn!("Could not fetch profile for {}: {:?}", user, err);
+                matrix::model::Profile {
+                    displayname: None,
+                    avatar_url: None
+                }
+            }
+        };
+        let realname = profile.displayname.unwrap_or(format!("{}", user));
+        self.client.send_numeric(311, vec![nick.clone(), identity::ident(&user), user.homeserver.clone(), "*".to_string()],
+                                 &realname).unwrap();
+        let channels = self.shared_channels(&user);
+        if channels.len() > 0 {
+            self.client.send_numeric(319, vec![nick.clone()], &channels.join(" ")).unwrap();
+        }
+        self.client.send_numeric(312, vec![nick.clone(), user.homeserver.clone()], "Matrix homeserver").unwrap();
+        if let Some(avatar) = profile.avatar_url {
+            self.client.send_numeric(320, vec![nick.clone()], &format!("has avatar {}", avatar)).unwrap();
+        }
+        self.client.send_numeric(330, vec![nick.clone(), format!("{}", user)], "is logged in as").unwrap();
+        self.client.send_numeric(318, vec![nick], "End of /WHOIS list.").unwrap();
+    }
+
+    fn handle_list(&mut self, message: &Message) {
+        if !self.client.is_registered() {
+            self.client.send_numeric(451, vec![], "You have not registered").unwrap();
+            return;
+        }
+        // "LIST *term*" searches the directory. Other ELIST conditions
+        // aren't something matrix can filter on.
+        let search = match message.param(0) {
+            Some(ref p) if !p.starts_with("<") && !p.starts_with(">") => {
+                let term = p.trim_matches('*').to_string();
+                if term.len() > 0 {
+                    Some(term)
+                } else {
+                    None
+                }
+            },
+            _ => None
+        };
+        let homeserver = self.matrix.uid.clone().unwrap().homeserver;
+        self.client.send_numeric(321, vec!["Channel".to_string()], "Users  Name").unwrap();
+        let mut since: Option<String> = None;
+        for _ in 0..LIST_MAX_PAGES {
+            let page = self.matrix.public_rooms(since.as_ref().map(|s| &**s),
+                                                search.as_ref().map(|s| &**s),
+                                                LIST_PAGE_SIZE);
+            let (rooms, next) = match page {
+                Ok(p) => p,
+                Err(err) => {
+                    warn!("Could not list public rooms: {:?}", err);
+                    break;
+                }
+            };
+            for room in rooms {
+                let channel = Room::channel_name(&room.id, room.canonical_alias.as_ref(), &room.aliases, &homeserver);
+                let topic = match (room.name, room.topic) {
+                    (Some(name), Some(topic)) => format!("[{}] {}", name, topic),
+                    (Some(name), None) => format!("[{}]", name),
+                    (None, Some(topic)) => topic,
+                    (None, None) => String::new()
+                };
+                self.client.send_numeric(322, vec![channel, format!("{}", room.num_joined_members)], &topic).unwrap();
+            }
+            match next {
+                Some(token) => since = Some(token),
+                None => break
+            }
+        }
+        self.client.send_numeric(323, vec![], "End of /LIST").unwrap();
+    }
+
+    fn handle_client(&mut self, events: &mut EventLoop<Bridge>) {
+        loop {
+            match self.client.read_message() {
+                None => return,
+                Some(message) => {
+                    match message.command {
+                        Command::Pass => {
+                            self.client.auth.set_password(message.args[0].clone())
+                        }
+                        Command::Nick => {
+                            self.handle_nick(&message, events);
+                        },
+                        Command::User => {
+                            self.client.auth.set_username(message.args[0].clone());
+                            self.register(events);
+                        },
+                        Command::Cap => {
+                            self.client.handle_cap(&message).expect("Could not send CAP reply");
+                            self.register(events);
+                        },
+                        Command::Authenticate => {
+                            self.handle_authenticate(&message);
+                        },
+                        Command::Chathistory => {
+                            self.handle_chathistory(&message);
+                        },
+                        Command::Join => {
+                            self.handle_join(&message);
+                        },
+                        Command::Part => {
+                            self.handle_part(&message);
+                        },
+                        Command::Topic => {
+                            self.handle_topic(&message);
+                        },
+                        Command::Names => {
+                            self.handle_names(&message);
+                        },
+                        Command::Mode => {
+                            self.handle_mode(&message);
+                        },
+                        Command::Kick => {
+                            self.handle_kick(&message);
+                        },
+                        Command::Invite => {
+                            self.handle_invite(&message);
+                        },
+                        Command::Who => {
+                            self.handle_who(&message);
+                        },
+                        Command::Whois => {
+                            self.handle_whois(&message);
+                        },
+                        Command::List => {
+                            self.handle_list(&message);
+                        },
+                        Command::Ping => {
+                            self.client.pong().expect("Could not send PONG");
+                        },
+                        Command::Quit => {
+                            // FIXME: Logout of matrix and exit thread
+                            return;
+                        },
+                        Command::Privmsg | Command::Notice => {
+                            self.handle_message(message);
+                        },
+                        _ =>
+                            warn!("unhandled {:?}", message)
+                    }
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::{Bridge, Room};
+    use config::{Config, NickStrategy};
+    use identity;
+    use matrix::model::{RoomID, UserID};
+    use matrix::events::PowerLevels;
+    use irc::protocol::{Command, Message};
+    use irc::streams::{AsEvented, Client, IrcStream};
+    use rustc_serialize::json::Json;
+    use hyper;
+    use mio::Evented;
+    use mio::tcp::TcpListener;
+    use std::io;
+    use std::io::{Read, Write};
+    use std::sync::{Arc, Mutex};
+
+    // A client connection that never says anything and keeps whatever the
+    // bridge sends it
+    struct TestStream {
+        listener: TcpListener,
+        sent: Arc<Mutex<Vec<u8>>>
+    }
+
+    impl Read for TestStream {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Ok(0)
+        }
+    }
+
+    impl Write for TestStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.sent.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl AsEvented for TestStream {
+        fn as_evented(&self) -> &Evented {
+            &self.listener
+        }
+    }
+
+    impl IrcStream for TestStream {}
+
+    fn unregistered_bridge() -> (Bridge, Arc<Mutex<Vec<u8>>>) {
+        let sent = Arc::new(Mutex::new(vec![]));
+        let stream = TestStream {
+            listener: TcpListener::bind(&"127.0.0.1:0".parse().unwrap()).unwrap(),
+            sent: sent.clone()
+        };
+        let bridge = Bridge::new(Client::new(Box::new(stream)),
+                                 hyper::Url::parse("https://example.org").unwrap(),
+                                 Config::new());
+        (bridge, sent)
+    }
+
+    fn sent_lines(sent: &Arc<Mutex<Vec<u8>>>) -> String {
+        String::from_utf8(sent.lock().unwrap().clone()).unwrap()
+    }
+
+    #[test]
+    fn names_reply_is_split() {
+        let mut room = Room::new(RoomID::from_str("!abc:example.org"));
+        room.irc_name = Some("#pto:example.org".to_string());
+        room.join_rules = Some("public".to_string());
+        for i in 0..200 {
+            room.members.insert(UserID::from_str(&format!("@user{}:example.org", i)));
+        }
+        let reply = room.names_reply("me");
+        assert!(reply.len() > 2);
+
+        let (names, end) = reply.split_at(reply.len() - 1);
+        assert_eq!(end[0].command, Command::Numeric(366));
+        assert_eq!(end[0].args, &["me", "#pto:example.org"]);
+        let mut seen = 0;
+        for msg in names {
+            assert_eq!(msg.command, Command::Numeric(353));
+            assert_eq!(msg.args, &["me", "=", "#pto:example.org"]);
+            assert!(msg.to_string().len() + 2 <= 512);
+            seen += msg.suffix.as_ref().unwrap().split(" ").count();
+        }
+        assert_eq!(seen, 200);
+    }
+
+    #[test]
+    fn empty_names_reply() {
+        let mut room = Room::new(RoomID::from_str("!abc:example.org"));
+        room.irc_name = Some("#pto:example.org".to_string());
+        let reply = room.names_reply("me");
+        assert_eq!(reply.len(), 1);
+        assert_eq!(reply[0].command, Command::Numeric(366));
+        assert_eq!(reply[0].args, &["me", "#pto:example.org"]);
+    }
+
+    #[test]
+    fn power_levels_as_modes() {
+        let mut room = Room::new(RoomID::from_str("!abc:example.org"));
+        room.irc_name = Some("#pto:example.org".to_string());
+        room.pending_sync = false;
+        for u in &["@alice:example.org", "@bob:example.org", "@carol:example.org"] {
+            room.members.insert(UserID::from_str(u));
+        }
+        let admin = UserID::from_str("@alice:example.org");
+        let levels = |json: &str| PowerLevels::from_json(&Json::from_str(json).unwrap());
+
+        let mut modes = vec![];
+        room.handle_power_levels(admin.clone(), levels(r#"{"users": {"@alice:example.org": 100, "@bob:example.org": 10, "@gone:example.org": 50}}"#),
+                                 &mut |msg| modes.push(msg.to_string()), 0);
+        assert_eq!(modes, &[":alice!alice@example.org MODE #pto:example.org +o alice",
+                            ":alice!alice@example.org MODE #pto:example.org +v bob"]);
+        assert_eq!(room.names_reply("me")[0].suffix, Some("@alice +bob carol".to_string()));
+
+        modes.clear();
+        room.handle_power_levels(admin, levels(r#"{"users": {"@alice:example.org": 100, "@bob:example.org": 50}}"#),
+                                 &mut |msg| modes.push(msg.to_string()), 0);
+        assert_eq!(modes, &[":alice!alice@example.org MODE #pto:example.org -v bob",
+                            ":alice!alice@example.org MODE #pto:example.org +o bob"]);
+    }
+
+    #[test]
+    fn required_levels() {
+        let mut room = Room::new(RoomID::from_str("!abc:example.org"));
+        room.me = Some(UserID::from_str("@alice:example.org"));
+        assert!(room.has_level(|l| l.ban()));
+
+        room.power_levels = Some(PowerLevels::from_json(&Json::from_str(
+            r#"{"users": {"@alice:example.org": 50}, "kick": 0, "ban": 100}"#).unwrap()));
+        assert!(room.has_level(|l| l.kick()));
+        assert!(room.has_level(|l| l.invite()));
+        assert!(room.has_level(|l| l.state_default()));
+        assert!(!room.has_level(|l| l.ban()));
+    }
+
+    #[test]
+    fn bans_kick_and_set_modes() {
+        let mut room = Room::new(RoomID::from_str("!abc:example.org"));
+        room.irc_name = Some("#pto:example.org".to_string());
+        room.members.insert(UserID::from_str("@bob:example.org"));
+        let alice = UserID::from_str("@alice:example.org");
+        let bob = UserID::from_str("@bob:example.org");
+
+        let mut msgs = vec![];
+        room.handle_ban(alice.clone(), bob.clone(), Some("spam".to_string()), &mut |msg| msgs.push(msg.to_string()), 0);
+        room.handle_unban(alice, bob, &mut |msg| msgs.push(msg.to_string()), 0);
+        assert_eq!(msgs, &[":alice!alice@example.org MODE #pto:example.org +b bob!*@example.org",
+                           ":alice!alice@example.org KICK #pto:example.org bob :spam",
+                           ":alice!alice@example.org MODE #pto:example.org -b bob!*@example.org"]);
+        assert!(room.members.is_empty());
+        assert!(room.bans.is_empty());
+    }
+
+    #[test]
+    fn ban_masks() {
+        let bob = UserID::from_str("@bob:example.org");
+        assert_eq!(Room::user_from_mask(&Room::ban_mask(&bob)), Some(bob));
+        assert_eq!(Room::user_from_mask("*!*@example.org"), None);
+        assert_eq!(Room::user_from_mask("bob!*@*"), None);
+        assert_eq!(Room::user_from_mask("bob"), None);
+    }
+
+    #[test]
+    fn displayname_nicks() {
+        let mut room = Room::new(RoomID::from_str("!abc:example.org"));
+        room.irc_name = Some("#pto:example.org".to_string());
+        room.nick_strategy = NickStrategy::Displayname;
+        room.pending_sync = false;
+        let alice = UserID::from_str("@alice:example.org");
+        let other_alice = UserID::from_str("@alice2:matrix.org");
+
+        let mut msgs = vec![];
+        room.update_nick(&alice, Some("Alice Liddell".to_string()), &mut |msg| msgs.push(msg.to_string()), 0);
+        room.handle_join(alice.clone(), &mut |msg| msgs.push(msg.to_string()), 0);
+        room.update_nick(&other_alice, Some("Alice Liddell".to_string()), &mut |msg| msgs.push(msg.to_string()), 0);
+        room.handle_join(other_alice.clone(), &mut |msg| msgs.push(msg.to_string()), 0);
+        room.update_nick(&alice, Some("Alice L.".to_string()), &mut |msg| msgs.push(msg.to_string()), 0);
+        assert_eq!(msgs, &[":AliceLiddell!alice@example.org JOIN #pto:example.org",
+                           ":AliceLiddell^^matrix!alice2@matrix.org JOIN #pto:example.org",
+                           ":AliceLiddell!alice@example.org NICK :AliceL_"]);
+
+        assert_eq!(room.user_from_nick("alicel_"), Some(alice));
+        assert_eq!(room.mention_to_matrix("AliceL_: hi"), "Alice L.: hi");
+        assert_eq!(room.mention_from_matrix("Alice L.: hi"), "AliceL_: hi");
+        assert_eq!(Room::sanitize_nick("1 nick/with=junk"), "_1nick_with_junk");
+
+        // Nobody gets to look like bob from matrix.org by picking his nick
+        let bob = UserID::from_str("@bob:matrix.org");
+        let encoded = identity::nick(&bob, "example.org");
+        for name in &["bob|matrix_org", "Bob|Matrix_org", "b^6fb|matrix_org"] {
+            let nick = Room::sanitize_nick(name);
+            assert!(nick.to_lowercase() != encoded);
+            assert!(!nick.contains("|") && !nick.contains("^"));
+        }
+    }
+
+    #[test]
+    fn clashing_displaynames() {
+        let mut room = Room::new(RoomID::from_str("!abc:example.org"));
+        room.me = Some(UserID::from_str("@me:example.org"));
+        room.nick_strategy = NickStrategy::Displayname;
+        let bob = UserID::from_str("@bob:example.org");
+        let mallory = UserID::from_str("@mallory:matrix.org");
+        let eve = UserID::from_str("@eve:matrix.org");
+        let trudy = UserID::from_str("@trudy:matrix.org");
+        for u in &[&bob, &mallory, &eve, &trudy] {
+            room.members.insert((*u).clone());
+        }
+
+        // bob already goes by bob, so everyone else calling themselves that
+        // has to take a nick that doesn't decode to anyone
+        let mut picked = vec![];
+        for u in &[&mallory, &eve, &trudy] {
+            let nick = room.pick_nick(u, "bob");
+            room.nicks.insert((*u).clone(), nick.clone());
+            picked.push(nick);
+        }
+        assert_eq!(picked, &["bob^^matrix", "bob^^matrix_org", "bob^^matrix_org2"]);
+        for (nick, user) in picked.iter().zip(&[&mallory, &eve, &trudy]) {
+            assert_eq!(identity::user_id(nick, "example.org"), None);
+            assert_eq!(room.user_from_nick(nick).as_ref(), Some(*user));
+        }
+        assert_eq!(room.user_from_nick("bob"), Some(bob));
+    }
+
+    #[test]
+    fn direct_rooms_are_queries() {
+        let me = UserID::from_str("@alice:example.org");
+        let bob = UserID::from_str("@bob:matrix.org");
+
+        let mut group = Room::new(RoomID::from_str("!group:example.org"));
+        group.me = Some(me.clone());
+        group.members.insert(me.clone());
+        group.members.insert(bob.clone());
+        group.update_irc_name(&me);
+        assert!(!group.is_pm);
+        assert_eq!(group.irc_name, Some("#group:example.org".to_string()));
+
+        let mut dm = Room::new(RoomID::from_str("!dm:example.org"));
+        dm.me = Some(me.clone());
+        dm.members.insert(me.clone());
+        dm.members.insert(bob.clone());
+        dm.direct_with = Some(bob.clone());
+        dm.update_irc_name(&me);
+        assert!(dm.is_pm);
+        assert_eq!(dm.irc_name, Some("bob|matrix_org".to_string()));
+
+        // The query follows the DM to whoever it's with now
+        let carol = UserID::from_str("@carol:example.org");
+        let mut msgs = vec![];
+        dm.pending_sync = false;
+        dm.members.insert(carol.clone());
+        dm.set_direct_with(Some(carol.clone()), &me, &mut |msg| msgs.push(msg.to_string()));
+        assert_eq!(msgs, &[":bob|matrix_org!bob@matrix.org NICK :carol"]);
+        assert!(dm.is_pm);
+        assert_eq!(dm.irc_name, Some("carol".to_string()));
+
+        // Later m.direct updates move an announced room between the two
+        msgs.clear();
+        group.pending_sync = false;
+        group.set_direct_with(Some(bob.clone()), &me, &mut |msg| msgs.push(msg.to_string()));
+        assert_eq!(msgs, &[":alice!alice@example.org PART #group:example.org :Moved to a private query"]);
+        assert!(group.is_pm);
+        assert_eq!(group.irc_name, Some("bob|matrix_org".to_string()));
+
+        msgs.clear();
+        group.set_direct_with(None, &me, &mut |msg| msgs.push(msg.to_string()));
+        assert!(!group.is_pm);
+        assert_eq!(group.irc_name, Some("#group:example.org".to_string()));
+        assert_eq!(msgs[0], ":alice!alice@example.org JOIN #group:example.org");
+    }
+
+    #[test]
+    fn who_and_whois_need_registration() {
+        let (mut bridge, sent) = unregistered_bridge();
+        bridge.handle_whois(&Message::from_str("WHOIS bob"));
+        bridge.handle_whois(&Message::from_str("WHOIS pto bob|matrix_org"));
+        bridge.handle_who(&Message::from_str("WHO bob"));
+        assert_eq!(sent_lines(&sent), ":pto 451 * :You have not registered\r\n\
+                                       :pto 451 * :You have not registered\r\n\
+                                       :pto 451 * :You have not registered\r\n");
+    }
+
+    #[test]
+    fn sasl_needs_the_cap() {
+        let (mut bridge, sent) = unregistered_bridge();
+        bridge.handle_authenticate(&Message::from_str("AUTHENTICATE PLAIN"));
+        assert_eq!(sent_lines(&sent), ":pto 904 * :SASL authentication failed\r\n");
+        assert!(!bridge.client.auth.in_sasl());
+    }
+
+    #[test]
+    fn modes_as_power_levels() {
+        let levels = PowerLevels::from_json(&Json::from_str(r#"{"users": {"@alice:example.org": 100}}"#).unwrap());
+        let alice = UserID::from_str("@alice:example.org");
+        let bob = UserID::from_str("@bob:example.org");
+        assert_eq!(Room::level_with_mode(&levels, &bob, true, 'o'), 50);
+        assert_eq!(Room::level_with_mode(&levels, &bob, true, 'v'), 10);
+        assert_eq!(Room::level_with_mode(&levels, &bob, false, 'o'), 0);
+        // Ops are already above voice
+        assert_eq!(Room::level_with_mode(&levels, &alice, true, 'v'), 100);
+        assert_eq!(Room::level_with_mode(&levels, &alice, false, 'v'), 100);
+        assert_eq!(Room::level_with_mode(&levels, &alice, false, 'o'), 0);
+    }
+}
@@ -0,0 +1,138 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+// Settings read from pto.conf. Each line is a setting, an optional channel it
+// applies to, and a value:
+//
+// # Replay 20 lines of history in every channel...
+// backlog = 20
+// # ...except this one
+// backlog #pto:oob.systems = 100
+// # Use room display names for nicks instead of matrix usernames
+// nicks = displayname
+#[derive(Clone, Debug)]
+pub struct Config {
+    backlog: usize,
+    channel_backlog: HashMap<String, usize>,
+    nicks: NickStrategy
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NickStrategy {
+    Localpart,
+    Displayname
+}
+
+impl Config {
+    pub fn new() -> Self {
+        Config {
+            backlog: 0,
+            channel_backlog: HashMap::new(),
+            nicks: NickStrategy::Localpart
+        }
+    }
+
+    pub fn load(path: &Path) -> Self {
+        let mut data = String::new();
+        match fs::File::open(path).and_then(|mut f| f.read_to_string(&mut data)) {
+            Ok(_) => Config::parse(&data),
+            Err(_) => {
+                debug!("No configuration found at {}, using defaults", path.display());
+                Config::new()
+            }
+        }
+    }
+
+    pub fn parse(data: &str) -> Self {
+        let mut config = Config::new();
+        for line in data.lines() {
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") {
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(idx) => (line[..idx].trim(), line[idx+1..].trim()),
+                None => {
+                    warn!("Ignoring malformed configuration line: {}", line);
+                    continue;
+                }
+            };
+            let key_parts: Vec<&str> = key.split_whitespace().collect();
+            if key_parts.len() == 0 || key_parts.len() > 2 {
+                warn!("Ignoring malformed configuration line: {}", line);
+                continue;
+            }
+            match key_parts[0] {
+                "backlog" => match (value.parse::<usize>(), key_parts.get(1)) {
+                    (Ok(count), None) =>
+                        config.backlog = count,
+                    (Ok(count), Some(channel)) => {
+                        config.channel_backlog.insert(channel.to_string(), count);
+                    },
+                    (Err(_), _) =>
+                        warn!("Ignoring invalid backlog length: {}", line)
+                },
+                "nicks" => match value {
+                    "localpart" => config.nicks = NickStrategy::Localpart,
+                    "displayname" => config.nicks = NickStrategy::Displayname,
+                    _ => warn!("Ignoring unknown nick strategy: {}", line)
+                },
+                _ => warn!("Ignoring unknown configuration line: {}", line)
+            }
+        }
+        config
+    }
+
+    pub fn nick_strategy(&self) -> NickStrategy {
+        self.nicks
+    }
+
+    pub fn backlog(&self, channel: &str) -> usize {
+        match self.channel_backlog.get(channel) {
+            Some(count) => *count,
+            None => self.backlog
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backlog() {
+        let config = Config::parse("# Comment\n\
+                                    backlog = 20\n\
+                                    backlog #pto:oob.systems = 100\n\
+                                    backlog = lots\n\
+                                    nonsense\n");
+        assert_eq!(config.backlog("#pto:oob.systems"), 100);
+        assert_eq!(config.backlog("#elsewhere:oob.systems"), 20);
+        assert_eq!(Config::new().backlog("#pto:oob.systems"), 0);
+    }
+
+    #[test]
+    fn nick_strategy() {
+        assert_eq!(Config::new().nick_strategy(), NickStrategy::Localpart);
+        assert_eq!(Config::parse("nicks = displayname").nick_strategy(), NickStrategy::Displayname);
+        assert_eq!(Config::parse("nicks = whatever").nick_strategy(), NickStrategy::Localpart);
+    }
+}
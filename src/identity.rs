@@ -0,0 +1,199 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// Maps matrix users to IRC nicks and back. Users on our own homeserver just
+// go by their localpart, while everyone else gets their homeserver tacked on
+// after a |, like alice|matrix_org.
+//
+// Anything that can't go in a nick is escaped as ^ and two hex digits per
+// byte. Nicks are case insensitive, so uppercase letters get escaped too, and
+// dots in homeservers become underscores to keep things readable. None of ^,
+// | or uppercase letters are allowed in matrix localparts, so this always
+// decodes back to the same user.
+//
+// That makes any nick with a | or an escape in it reserved for whoever it
+// decodes to. Clients can still pick one for themselves, but nicks we hand
+// out for other reasons, like display names, must never look like one.
+
+use matrix::model::UserID;
+
+fn is_nick_char(c: char) -> bool {
+    match c {
+        'a'...'z' | '0'...'9' | '[' | ']' | '\\' | '`' | '_' | '{' | '}' | '-' => true,
+        _ => false
+    }
+}
+
+// What clients are allowed to pick for themselves, which is a little looser
+// than what we generate.
+pub fn is_valid_nick(nick: &str) -> bool {
+    match nick.chars().next() {
+        None | Some('-') => false,
+        Some(c) if c.is_digit(10) => false,
+        _ => nick.len() <= 30 && nick.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '[' | ']' | '\\' | '`' | '_' | '^' | '{' | '|' | '}' | '-' => true,
+            _ => false
+        })
+    }
+}
+
+fn escape(c: char, out: &mut String) {
+    for b in c.to_string().as_bytes() {
+        out.push_str(&format!("^{:02x}", b));
+    }
+}
+
+fn encode_localpart(localpart: &str) -> String {
+    let mut ret = String::new();
+    for c in localpart.chars() {
+        // Nicks can't start with a digit or a dash
+        let leading = ret.len() == 0 && (c == '-' || c.is_digit(10));
+        if is_nick_char(c) && !leading {
+            ret.push(c);
+        } else {
+            escape(c, &mut ret);
+        }
+    }
+    ret
+}
+
+fn encode_homeserver(homeserver: &str) -> String {
+    let mut ret = String::new();
+    for c in homeserver.chars() {
+        match c {
+            '.' => ret.push('_'),
+            '_' => escape(c, &mut ret),
+            c if is_nick_char(c) => ret.push(c),
+            c => escape(c, &mut ret)
+        }
+    }
+    ret
+}
+
+fn decode(encoded: &str, dots: bool) -> Option<String> {
+    let mut bytes: Vec<u8> = vec![];
+    let mut chars = encoded.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '^' => {
+                let hex: String = chars.by_ref().take(2).collect();
+                match u8::from_str_radix(&hex, 16) {
+                    Ok(b) if hex.len() == 2 => bytes.push(b),
+                    _ => return None
+                }
+            },
+            '_' if dots => bytes.push(b'.'),
+            c => bytes.extend(c.to_lowercase().collect::<String>().as_bytes())
+        }
+    }
+    match String::from_utf8(bytes) {
+        Ok(ref s) if s.len() == 0 => None,
+        Ok(s) => Some(s),
+        Err(_) => None
+    }
+}
+
+pub fn nick(user: &UserID, homeserver: &str) -> String {
+    if user.homeserver == homeserver {
+        encode_localpart(&user.nickname)
+    } else {
+        format!("{}|{}", encode_localpart(&user.nickname), encode_homeserver(&user.homeserver))
+    }
+}
+
+pub fn ident(user: &UserID) -> String {
+    encode_localpart(&user.nickname)
+}
+
+pub fn mask(user: &UserID, homeserver: &str) -> String {
+    format!("{}!{}@{}", nick(user, homeserver), ident(user), user.homeserver)
+}
+
+pub fn user_id(nick: &str, homeserver: &str) -> Option<UserID> {
+    let (localpart, server) = match nick.find('|') {
+        Some(idx) => (decode(&nick[..idx], false), decode(&nick[idx+1..], true)),
+        None => (decode(nick, false), Some(homeserver.to_string()))
+    };
+    match (localpart, server) {
+        (Some(l), Some(s)) => Some(UserID {
+            nickname: l,
+            homeserver: s
+        }),
+        _ => None
+    }
+}
+
+pub fn is_reserved(nick: &str) -> bool {
+    match nick.find('|') {
+        Some(idx) => decode(&nick[..idx], false).is_some() && decode(&nick[idx+1..], true).is_some(),
+        None => nick.contains('^') && decode(nick, false).is_some()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix::model::UserID;
+
+    #[test]
+    fn local_and_remote_nicks() {
+        let alice = UserID::from_str("@alice:oob.systems");
+        let remote = UserID::from_str("@alice:matrix.org");
+        assert_eq!(nick(&alice, "oob.systems"), "alice");
+        assert_eq!(nick(&remote, "oob.systems"), "alice|matrix_org");
+        assert_eq!(mask(&remote, "oob.systems"), "alice|matrix_org!alice@matrix.org");
+        assert_eq!(user_id("alice", "oob.systems"), Some(alice));
+        assert_eq!(user_id("Alice|Matrix_org", "oob.systems"), Some(remote));
+    }
+
+    #[test]
+    fn escaping_round_trips() {
+        for uid in &["@some.one/else=:oob.systems", "@1234:oob.systems", "@-dash:oob.systems",
+                     "@under_score:my_server.example.org", "@Shouty:oob.systems"] {
+            let user = UserID::from_str(uid);
+            let encoded = nick(&user, "elsewhere.org");
+            assert!(encoded.chars().all(|c| c.is_alphanumeric() || "[]\\`_^{|}-".contains(c)));
+            assert!(!encoded.starts_with("-") && !encoded.chars().next().unwrap().is_digit(10));
+            assert_eq!(user_id(&encoded, "elsewhere.org"), Some(user.clone()));
+            assert_eq!(user_id(&ident(&user), &user.homeserver), Some(user.clone()));
+        }
+        assert_eq!(user_id("broken^z", "oob.systems"), None);
+        assert_eq!(user_id("|oob_systems", "oob.systems"), None);
+    }
+
+    #[test]
+    fn reserved_nicks() {
+        let remote = UserID::from_str("@alice:matrix.org");
+        let escaped = UserID::from_str("@some.one:oob.systems");
+        assert!(is_reserved(&nick(&remote, "oob.systems")));
+        assert!(is_reserved(&nick(&escaped, "oob.systems")));
+        assert!(is_reserved("Bob|Matrix_org"));
+        assert!(!is_reserved("alice"));
+        assert!(!is_reserved("bob^^matrix"));
+        assert!(!is_reserved("broken^z"));
+    }
+
+    #[test]
+    fn valid_nicks() {
+        assert!(is_valid_nick("tdfischer"));
+        assert!(is_valid_nick("Alice|away"));
+        assert!(!is_valid_nick(""));
+        assert!(!is_valid_nick("1337"));
+        assert!(!is_valid_nick("-dash"));
+        assert!(!is_valid_nick("has space"));
+        assert!(!is_valid_nick("way_too_long_for_anyone_to_type_out"));
+    }
+}
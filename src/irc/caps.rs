@@ -0,0 +1,204 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::collections::BTreeSet;
+use irc::protocol::{Command, Message, Tags};
+
+// Capabilities we actually implement, along with the value advertised to
+// clients that speak CAP 302.
+const SUPPORTED: &'static [(&'static str, Option<&'static str>)] = &[
+    ("sasl", Some("PLAIN,EXTERNAL")),
+    ("message-tags", None),
+    ("server-time", None),
+    ("batch", None),
+    ("draft/chathistory", None)
+];
+
+#[derive(Debug, PartialEq)]
+enum NegotiationState {
+    Idle,
+    Negotiating,
+    Finished
+}
+
+#[derive(Debug)]
+pub struct CapSession {
+    state: NegotiationState,
+    version: u32,
+    enabled: BTreeSet<String>
+}
+
+impl CapSession {
+    pub fn new() -> Self {
+        CapSession {
+            state: NegotiationState::Idle,
+            version: 301,
+            enabled: BTreeSet::new()
+        }
+    }
+
+    pub fn is_negotiating(&self) -> bool {
+        self.state == NegotiationState::Negotiating
+    }
+
+    pub fn is_enabled(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    pub fn enabled(&self) -> &BTreeSet<String> {
+        &self.enabled
+    }
+
+    fn is_supported(cap: &str) -> bool {
+        SUPPORTED.iter().any(|&(name, _)| name == cap)
+    }
+
+    fn advertised(&self) -> String {
+        let mut caps: Vec<String> = vec![];
+        for &(name, value) in SUPPORTED {
+            match value {
+                Some(v) if self.version >= 302 =>
+                    caps.push(format!("{}={}", name, v)),
+                _ =>
+                    caps.push(name.to_string())
+            }
+        }
+        caps.join(" ")
+    }
+
+    // Registration is held off from the first LS or REQ until the client
+    // sends END. Clients that poke at CAP after registration don't get to
+    // block it again.
+    fn start(&mut self) {
+        if self.state == NegotiationState::Idle {
+            self.state = NegotiationState::Negotiating;
+        }
+    }
+
+    // REQ is all or nothing, so nothing changes unless every cap is known.
+    fn request(&mut self, requested: &str) -> bool {
+        let mut changes: Vec<(bool, String)> = vec![];
+        for cap in requested.split_whitespace() {
+            let (enable, name) = if cap.starts_with("-") {
+                (false, &cap[1..])
+            } else {
+                (true, cap)
+            };
+            if !CapSession::is_supported(name) {
+                return false;
+            }
+            changes.push((enable, name.to_string()));
+        }
+        for (enable, name) in changes {
+            if enable {
+                self.enabled.insert(name);
+            } else {
+                self.enabled.remove(&name);
+            }
+        }
+        true
+    }
+
+    fn reply(nickname: &str, subcommand: &str, caps: String) -> Message {
+        Message {
+            tags: Tags::new(),
+            prefix: Some("pto".to_string()),
+            command: Command::Cap,
+            args: vec![nickname.to_string(), subcommand.to_string()],
+            suffix: Some(caps)
+        }
+    }
+
+    pub fn handle(&mut self, nickname: &str, message: &Message) -> Vec<Message> {
+        let subcommand = message.param(0).unwrap_or(String::new()).to_uppercase();
+        match &*subcommand {
+            "LS" => {
+                self.start();
+                if let Some(version) = message.param(1) {
+                    self.version = version.parse().unwrap_or(301);
+                }
+                vec![CapSession::reply(nickname, "LS", self.advertised())]
+            },
+            "LIST" => {
+                let enabled: Vec<String> = self.enabled.iter().cloned().collect();
+                vec![CapSession::reply(nickname, "LIST", enabled.join(" "))]
+            },
+            "REQ" => {
+                self.start();
+                let requested = message.param(1).unwrap_or(String::new());
+                if self.request(&requested) {
+                    vec![CapSession::reply(nickname, "ACK", requested)]
+                } else {
+                    vec![CapSession::reply(nickname, "NAK", requested)]
+                }
+            },
+            "END" => {
+                if self.is_negotiating() {
+                    self.state = NegotiationState::Finished;
+                }
+                vec![]
+            },
+            _ => vec![Message {
+                tags: Tags::new(),
+                prefix: Some("pto".to_string()),
+                command: Command::Numeric(410),
+                args: vec![nickname.to_string(), subcommand.clone()],
+                suffix: Some("Invalid CAP command".to_string())
+            }]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use irc::protocol::{Command, Message};
+
+    #[test]
+    fn negotiation_blocks_until_end() {
+        let mut caps = CapSession::new();
+        assert!(!caps.is_negotiating());
+
+        let replies = caps.handle("*", &Message::from_str("CAP LS 302"));
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].command, Command::Cap);
+        assert_eq!(replies[0].args, &["*", "LS"]);
+        assert!(caps.is_negotiating());
+
+        assert_eq!(caps.handle("*", &Message::from_str("CAP END")).len(), 0);
+        assert!(!caps.is_negotiating());
+
+        caps.handle("nick", &Message::from_str("CAP LS"));
+        assert!(!caps.is_negotiating());
+    }
+
+    #[test]
+    fn unknown_caps_are_refused() {
+        let mut caps = CapSession::new();
+        let replies = caps.handle("*", &Message::from_str("CAP REQ :not-a-real-cap"));
+        assert_eq!(replies[0].args, &["*", "NAK"]);
+        assert_eq!(replies[0].suffix, Some("not-a-real-cap".to_string()));
+        assert!(caps.enabled().is_empty());
+    }
+
+    #[test]
+    fn invalid_subcommand() {
+        let mut caps = CapSession::new();
+        let replies = caps.handle("*", &Message::from_str("CAP FROB"));
+        assert_eq!(replies[0].command, Command::Numeric(410));
+        assert_eq!(replies[0].args, &["*", "FROB"]);
+    }
+}
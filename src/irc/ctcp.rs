@@ -0,0 +1,109 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+use std::time::{SystemTime, UNIX_EPOCH};
+use irc::util;
+
+const DELIM: char = '\x01';
+
+// Everything we answer, for CLIENTINFO
+const SUPPORTED: &'static str = "ACTION CLIENTINFO PING TIME VERSION";
+
+#[derive(Debug, PartialEq)]
+pub struct Ctcp {
+    pub command: String,
+    pub params: Option<String>
+}
+
+impl Ctcp {
+    // Lots of clients leave off the closing \x01, so that's optional.
+    pub fn parse(text: &str) -> Option<Self> {
+        if !text.starts_with(DELIM) {
+            return None;
+        }
+        let inner = text[1..].trim_right_matches(DELIM);
+        let (command, params) = match inner.find(' ') {
+            Some(idx) => (&inner[..idx], Some(inner[idx+1..].to_string())),
+            None => (inner, None)
+        };
+        Some(Ctcp {
+            command: command.to_uppercase(),
+            params: params
+        })
+    }
+
+    pub fn action(text: &str) -> String {
+        Ctcp {
+            command: "ACTION".to_string(),
+            params: Some(text.to_string())
+        }.to_string()
+    }
+
+    pub fn to_string(&self) -> String {
+        match self.params {
+            Some(ref params) => format!("{}{} {}{}", DELIM, self.command, params, DELIM),
+            None => format!("{}{}{}", DELIM, self.command, DELIM)
+        }
+    }
+
+    // The bridge answers these itself, since there's nothing on the matrix
+    // side that would. Anything else doesn't get a reply.
+    pub fn reply(&self) -> Option<Ctcp> {
+        let params = match &*self.command {
+            "VERSION" => Some(format!("PTO {}", version!())),
+            "PING" => self.params.clone(),
+            "TIME" => {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+                Some(util::format_timestamp(now.as_secs() * 1000 + (now.subsec_nanos() / 1000000) as u64))
+            },
+            "CLIENTINFO" => Some(SUPPORTED.to_string()),
+            _ => return None
+        };
+        Some(Ctcp {
+            command: self.command.clone(),
+            params: params
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ctcp() {
+        assert_eq!(Ctcp::parse("\x01ACTION waves\x01"), Some(Ctcp {
+            command: "ACTION".to_string(),
+            params: Some("waves".to_string())
+        }));
+        assert_eq!(Ctcp::parse("\x01version"), Some(Ctcp {
+            command: "VERSION".to_string(),
+            params: None
+        }));
+        assert_eq!(Ctcp::parse("just talking"), None);
+        assert_eq!(Ctcp::action("waves"), "\x01ACTION waves\x01");
+    }
+
+    #[test]
+    fn replies() {
+        let ping = Ctcp::parse("\x01PING 1234\x01").unwrap();
+        assert_eq!(ping.reply().unwrap().to_string(), "\x01PING 1234\x01");
+        let info = Ctcp::parse("\x01CLIENTINFO\x01").unwrap();
+        assert_eq!(info.reply().unwrap().params, Some("ACTION CLIENTINFO PING TIME VERSION".to_string()));
+        assert!(Ctcp::parse("\x01TIME\x01").unwrap().reply().unwrap().params.unwrap().ends_with("Z"));
+        assert_eq!(Ctcp::parse("\x01DCC SEND file\x01").unwrap().reply(), None);
+    }
+}
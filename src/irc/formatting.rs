@@ -0,0 +1,626 @@
+/*
+ * Copyright 2015-2016 Torrie Fischer <tdfischer@hackerbots.net>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ */
+
+// Converts between mIRC formatting codes and the HTML matrix clients send in
+// formatted_body.
+
+const BOLD: char = '\x02';
+const COLOR: char = '\x03';
+const RESET: char = '\x0f';
+const MONOSPACE: char = '\x11';
+const REVERSE: char = '\x16';
+const ITALIC: char = '\x1d';
+const STRIKETHROUGH: char = '\x1e';
+const UNDERLINE: char = '\x1f';
+
+// The classic 16 mIRC colors
+const PALETTE: [(u8, u8, u8); 16] = [
+    (0xff, 0xff, 0xff), (0x00, 0x00, 0x00), (0x00, 0x00, 0x7f), (0x00, 0x93, 0x00),
+    (0xff, 0x00, 0x00), (0x7f, 0x00, 0x00), (0x9c, 0x00, 0x9c), (0xfc, 0x7f, 0x00),
+    (0xff, 0xff, 0x00), (0x00, 0xfc, 0x00), (0x00, 0x93, 0x93), (0x00, 0xff, 0xff),
+    (0x00, 0x00, 0xfc), (0xff, 0x00, 0xff), (0x7f, 0x7f, 0x7f), (0xd2, 0xd2, 0xd2)
+];
+
+fn color_to_html(color: u8) -> String {
+    let (r, g, b) = PALETTE[color as usize];
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+// Picks whichever mIRC color is closest to an HTML #rrggbb color
+fn color_from_html(color: &str) -> Option<u8> {
+    let hex = color.trim().trim_left_matches('#');
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_digit(16)) {
+        return None;
+    }
+    let channel = |idx: usize| u8::from_str_radix(&hex[idx..idx+2], 16).ok();
+    let (r, g, b) = match (channel(0), channel(2), channel(4)) {
+        (Some(r), Some(g), Some(b)) => (r as i32, g as i32, b as i32),
+        _ => return None
+    };
+    let mut best: Option<(u8, i32)> = None;
+    for (idx, &(pr, pg, pb)) in PALETTE.iter().enumerate() {
+        let distance = (r - pr as i32).pow(2) + (g - pg as i32).pow(2) + (b - pb as i32).pow(2);
+        match best {
+            Some((_, d)) if d <= distance => (),
+            _ => best = Some((idx as u8, distance))
+        }
+    }
+    best.map(|(idx, _)| idx)
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Style {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+    strikethrough: bool,
+    monospace: bool,
+    color: Option<u8>
+}
+
+// Splits IRC text into runs of the same style, dropping the control codes
+fn parse_codes(text: &str) -> Vec<(Style, String)> {
+    let mut ret: Vec<(Style, String)> = vec![];
+    let mut style = Style::default();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            BOLD => style.bold = !style.bold,
+            ITALIC => style.italic = !style.italic,
+            UNDERLINE => style.underline = !style.underline,
+            STRIKETHROUGH => style.strikethrough = !style.strikethrough,
+            MONOSPACE => style.monospace = !style.monospace,
+            REVERSE => (),
+            RESET => style = Style::default(),
+            COLOR => {
+                let mut fg = String::new();
+                while fg.len() < 2 && chars.peek().map(|c| c.is_digit(10)).unwrap_or(false) {
+                    fg.push(chars.next().unwrap());
+                }
+                // A background only counts if there's a number after the comma
+                if fg.len() > 0 && chars.peek() == Some(&',') {
+                    let mut lookahead = chars.clone();
+                    lookahead.next();
+                    if lookahead.peek().map(|c| c.is_digit(10)).unwrap_or(false) {
+                        chars.next();
+                        let mut bg = 0;
+                        while bg < 2 && chars.peek().map(|c| c.is_digit(10)).unwrap_or(false) {
+                            chars.next();
+                            bg += 1;
+                        }
+                    }
+                }
+                style.color = match fg.parse::<u8>() {
+                    Ok(color) if (color as usize) < PALETTE.len() => Some(color),
+                    _ => None
+                };
+            },
+            c => {
+                let same_style = match ret.last() {
+                    Some(&(ref s, _)) => *s == style,
+                    None => false
+                };
+                if same_style {
+                    ret.last_mut().unwrap().1.push(c);
+                } else {
+                    ret.push((style.clone(), c.to_string()));
+                }
+            }
+        }
+    }
+    ret
+}
+
+fn escape_html(text: &str) -> String {
+    let mut ret = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => ret.push_str("&amp;"),
+            '<' => ret.push_str("&lt;"),
+            '>' => ret.push_str("&gt;"),
+            '"' => ret.push_str("&quot;"),
+            '\'' => ret.push_str("&#39;"),
+            c => ret.push(c)
+        }
+    }
+    ret
+}
+
+pub fn strip_codes(text: &str) -> String {
+    parse_codes(text).into_iter().map(|(_, t)| t).collect()
+}
+
+// Only returns HTML when there's actually some formatting, so plain messages
+// stay plain.
+pub fn irc_to_html(text: &str) -> Option<String> {
+    let runs = parse_codes(text);
+    if runs.iter().all(|&(ref style, _)| *style == Style::default()) {
+        return None;
+    }
+    let mut ret = String::new();
+    for (style, text) in runs {
+        let mut tags: Vec<(&str, String)> = vec![];
+        if let Some(color) = style.color {
+            tags.push(("font", format!("<font color=\"{}\">", color_to_html(color))));
+        }
+        if style.bold {
+            tags.push(("b", "<b>".to_string()));
+        }
+        if style.italic {
+            tags.push(("i", "<i>".to_string()));
+        }
+        if style.underline {
+            tags.push(("u", "<u>".to_string()));
+        }
+        if style.strikethrough {
+            tags.push(("del", "<del>".to_string()));
+        }
+        if style.monospace {
+            tags.push(("code", "<code>".to_string()));
+        }
+        for &(_, ref open) in &tags {
+            ret.push_str(open);
+        }
+        ret.push_str(&escape_html(&text));
+        for &(name, _) in tags.iter().rev() {
+            ret.push_str(&format!("</{}>", name));
+        }
+    }
+    Some(ret)
+}
+
+fn decode_entity(entity: &str) -> Option<String> {
+    match entity {
+        "amp" => Some("&".to_string()),
+        "lt" => Some("<".to_string()),
+        "gt" => Some(">".to_string()),
+        "quot" => Some("\"".to_string()),
+        "apos" => Some("'".to_string()),
+        "nbsp" => Some(" ".to_string()),
+        e if e.starts_with("#x") || e.starts_with("#X") =>
+            u32::from_str_radix(&e[2..], 16).ok().and_then(::std::char::from_u32).map(|c| c.to_string()),
+        e if e.starts_with("#") =>
+            e[1..].parse::<u32>().ok().and_then(::std::char::from_u32).map(|c| c.to_string()),
+        _ => None
+    }
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut ret = String::new();
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        ret.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        let decoded = match rest.find(';') {
+            Some(semi) if semi < 10 => decode_entity(&rest[1..semi]).map(|d| (d, semi)),
+            _ => None
+        };
+        match decoded {
+            Some((d, semi)) => {
+                ret.push_str(&d);
+                rest = &rest[semi+1..];
+            },
+            None => {
+                ret.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    ret.push_str(rest);
+    ret
+}
+
+fn attribute(tag: &str, name: &str) -> Option<String> {
+    let mut rest = tag;
+    while let Some(idx) = rest.find(name) {
+        let preceded_by_space = idx > 0 && rest[..idx].ends_with(char::is_whitespace);
+        let after = rest[idx + name.len()..].trim_left();
+        if preceded_by_space && after.starts_with('=') {
+            let value = after[1..].trim_left();
+            return match value.chars().next() {
+                Some(quote) if quote == '"' || quote == '\'' =>
+                    value[1..].find(quote).map(|end| decode_entities(&value[1..end+1])),
+                _ =>
+                    Some(value.split(|c: char| c.is_whitespace() || c == '>').next().unwrap_or("").to_string())
+            };
+        }
+        rest = &rest[idx + name.len()..];
+    }
+    None
+}
+
+struct HtmlRenderer {
+    out: String,
+    // Whatever codes are switched on at this point in the current line
+    emitted: Style,
+    bold: usize,
+    italic: usize,
+    underline: usize,
+    strikethrough: usize,
+    monospace: usize,
+    colors: Vec<Option<u8>>,
+    // Each open link's href, and the text shown for it so far
+    links: Vec<(Option<String>, String)>,
+    lists: Vec<Option<u32>>,
+    quote_depth: usize,
+    in_pre: bool,
+    skip_depth: usize
+}
+
+impl HtmlRenderer {
+    fn new() -> Self {
+        HtmlRenderer {
+            out: String::new(),
+            emitted: Style::default(),
+            bold: 0,
+            italic: 0,
+            underline: 0,
+            strikethrough: 0,
+            monospace: 0,
+            colors: vec![],
+            links: vec![],
+            lists: vec![],
+            quote_depth: 0,
+            in_pre: false,
+            skip_depth: 0
+        }
+    }
+
+    fn style(&self) -> Style {
+        Style {
+            bold: self.bold > 0,
+            italic: self.italic > 0,
+            underline: self.underline > 0,
+            strikethrough: self.strikethrough > 0,
+            monospace: self.monospace > 0 || self.in_pre,
+            color: self.colors.iter().rev().filter_map(|c| *c).next()
+        }
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.out.len() == 0 || self.out.ends_with('\n')
+    }
+
+    fn trim_spaces(&mut self) {
+        while self.out.ends_with(' ') {
+            self.out.pop();
+        }
+    }
+
+    // Codes only get written right before the text they apply to, so there's
+    // nothing to undo when a tag closes without any text in it.
+    fn switch_style(&mut self, style: Style, next: &str) {
+        let toggles = [
+            (self.emitted.bold, style.bold, BOLD),
+            (self.emitted.italic, style.italic, ITALIC),
+            (self.emitted.underline, style.underline, UNDERLINE),
+            (self.emitted.strikethrough, style.strikethrough, STRIKETHROUGH),
+            (self.emitted.monospace, style.monospace, MONOSPACE)
+        ];
+        for &(was, is, code) in toggles.iter() {
+            if was != is {
+                self.out.push(code);
+            }
+        }
+        if self.emitted.color != style.color {
+            // A pair of bolds keeps the text after a color code from being
+            // read as more of the color
+            let ambiguous = match style.color {
+                Some(c) => {
+                    self.out.push_str(&format!("{}{:02}", COLOR, c));
+                    next.starts_with(',')
+                },
+                None => {
+                    self.out.push(COLOR);
+                    next.starts_with(|c: char| c.is_digit(10))
+                }
+            };
+            if ambiguous {
+                self.out.push(BOLD);
+                self.out.push(BOLD);
+            }
+        }
+        self.emitted = style;
+    }
+
+    // Every line goes out as its own IRC message, and formatting doesn't
+    // carry over between messages. Everything gets switched off here and
+    // back on again before the next bit of text.
+    fn line_break(&mut self) {
+        self.switch_style(Style::default(), "");
+        self.out.push('\n');
+    }
+
+    // Starts a new line, unless we're already at the start of one
+    fn newline(&mut self) {
+        self.trim_spaces();
+        if !self.at_line_start() {
+            self.line_break();
+        }
+    }
+
+    fn push_str(&mut self, text: &str) {
+        for (idx, line) in text.split('\n').enumerate() {
+            if idx > 0 {
+                self.line_break();
+            }
+            if line.len() == 0 {
+                continue;
+            }
+            if self.at_line_start() {
+                for _ in 0..self.quote_depth {
+                    self.out.push_str("> ");
+                }
+            }
+            let style = self.style();
+            self.switch_style(style, line);
+            self.out.push_str(line);
+            for link in self.links.iter_mut() {
+                link.1.push_str(line);
+            }
+        }
+    }
+
+    fn text(&mut self, text: &str) {
+        if self.skip_depth > 0 {
+            return;
+        }
+        let text = decode_entities(text);
+        if self.in_pre {
+            self.push_str(&text);
+        } else {
+            // Outside of <pre>, HTML whitespace is all just spaces
+            let mut collapsed = String::new();
+            for c in text.chars() {
+                if c.is_whitespace() {
+                    let after_space = if collapsed.len() == 0 {
+                        self.at_line_start() || self.out.ends_with(' ')
+                    } else {
+                        collapsed.ends_with(' ')
+                    };
+                    if !after_space {
+                        collapsed.push(' ');
+                    }
+                } else {
+                    collapsed.push(c);
+                }
+            }
+            self.push_str(&collapsed);
+        }
+    }
+
+    fn open(&mut self, name: &str, tag: &str) {
+        if name == "mx-reply" {
+            self.skip_depth += 1;
+        }
+        if self.skip_depth > 0 {
+            return;
+        }
+        match name {
+            "b" | "strong" => self.bold += 1,
+            "i" | "em" => self.italic += 1,
+            "u" | "ins" => self.underline += 1,
+            "del" | "s" | "strike" => self.strikethrough += 1,
+            "code" => self.monospace += 1,
+            "pre" => {
+                self.newline();
+                self.in_pre = true;
+            },
+            "font" | "span" => {
+                let color = attribute(tag, "data-mx-color").or(attribute(tag, "color"))
+                    .and_then(|c| color_from_html(&c));
+                self.colors.push(color);
+            },
+            "a" => self.links.push((attribute(tag, "href"), String::new())),
+            "blockquote" => {
+                self.newline();
+                self.quote_depth += 1;
+            },
+            "ul" => {
+                self.newline();
+                self.lists.push(None);
+            },
+            "ol" => {
+                self.newline();
+                let start = attribute(tag, "start").and_then(|s| s.parse().ok()).unwrap_or(1);
+                self.lists.push(Some(start));
+            },
+            "li" => {
+                self.newline();
+                let bullet = match self.lists.last_mut() {
+                    Some(&mut Some(ref mut n)) => {
+                        let bullet = format!("{}. ", *n);
+                        // start comes from whoever sent the message
+                        *n = n.saturating_add(1);
+                        bullet
+                    },
+                    _ => "• ".to_string()
+                };
+                self.push_str(&bullet);
+            },
+            "br" => {
+                self.trim_spaces();
+                self.line_break();
+            },
+            "p" | "div" => self.newline(),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.newline();
+                self.bold += 1;
+            },
+            "img" => {
+                if let Some(alt) = attribute(tag, "alt") {
+                    self.push_str(&alt);
+                }
+            },
+            _ => ()
+        }
+    }
+
+    fn close(&mut self, name: &str) {
+        if self.skip_depth > 0 {
+            if name == "mx-reply" {
+                self.skip_depth -= 1;
+            }
+            return;
+        }
+        match name {
+            "b" | "strong" => self.bold = self.bold.saturating_sub(1),
+            "i" | "em" => self.italic = self.italic.saturating_sub(1),
+            "u" | "ins" => self.underline = self.underline.saturating_sub(1),
+            "del" | "s" | "strike" => self.strikethrough = self.strikethrough.saturating_sub(1),
+            "code" => self.monospace = self.monospace.saturating_sub(1),
+            "pre" => {
+                self.in_pre = false;
+                self.newline();
+            },
+            "font" | "span" => {
+                self.colors.pop();
+            },
+            "a" => {
+                if let Some((Some(href), shown)) = self.links.pop() {
+                    // Mentions are already showing the nick, and bare links
+                    // are already showing the link
+                    if !href.starts_with("https://matrix.to/") && shown.trim() != href && href.len() > 0 {
+                        self.push_str(&format!(" ({})", href));
+                    }
+                }
+            },
+            "blockquote" => {
+                self.newline();
+                if self.quote_depth > 0 {
+                    self.quote_depth -= 1;
+                }
+            },
+            "ul" | "ol" => {
+                self.lists.pop();
+                self.newline();
+            },
+            "p" | "div" => self.newline(),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.bold = self.bold.saturating_sub(1);
+                self.newline();
+            },
+            _ => ()
+        }
+    }
+}
+
+// Renders matrix HTML with mIRC codes. Block elements turn into separate
+// lines, which need to be sent as separate messages.
+pub fn html_to_irc(html: &str) -> String {
+    let mut renderer = HtmlRenderer::new();
+    let mut rest = html;
+    while rest.len() > 0 {
+        if rest.starts_with("<!--") {
+            rest = match rest.find("-->") {
+                Some(end) => &rest[end+3..],
+                None => ""
+            };
+            continue;
+        }
+        if rest.starts_with('<') {
+            if let Some(end) = rest.find('>') {
+                let tag = &rest[1..end];
+                let closing = tag.starts_with('/');
+                let body = tag.trim_left_matches('/');
+                let name: String = body.chars()
+                    .take_while(|c| !c.is_whitespace() && *c != '/')
+                    .collect::<String>()
+                    .to_lowercase();
+                if closing {
+                    renderer.close(&name);
+                } else {
+                    renderer.open(&name, body);
+                }
+                rest = &rest[end+1..];
+                continue;
+            }
+        }
+        let first = rest.chars().next().unwrap().len_utf8();
+        let next = match rest[first..].find('<') {
+            Some(idx) => idx + first,
+            None => rest.len()
+        };
+        renderer.text(&rest[..next]);
+        rest = &rest[next..];
+    }
+    renderer.trim_spaces();
+    renderer.switch_style(Style::default(), "");
+    renderer.out.trim_right().to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::Read;
+    use std::path;
+
+    // Fixtures write control codes the way they'd be written in rust, since
+    // they're invisible otherwise.
+    fn unescape(line: &str) -> String {
+        line.replace("\\x02", "\x02")
+            .replace("\\x03", "\x03")
+            .replace("\\x0f", "\x0f")
+            .replace("\\x11", "\x11")
+            .replace("\\x16", "\x16")
+            .replace("\\x1d", "\x1d")
+            .replace("\\x1e", "\x1e")
+            .replace("\\x1f", "\x1f")
+            .replace("\\n", "\n")
+    }
+
+    // Each case is a pair of lines, starting with the input and then the
+    // expected output. Lines starting with # are comments.
+    fn load_corpus(fixture: &str) -> Vec<(String, String)> {
+        let path = path::PathBuf::from("src/irc/test-fixtures/").join(fixture);
+        let mut data = String::new();
+        fs::File::open(path.as_path()).unwrap().read_to_string(&mut data).unwrap();
+        let lines: Vec<&str> = data.lines()
+            .filter(|l| l.len() > 0 && !l.starts_with("#"))
+            .collect();
+        assert_eq!(lines.len() % 2, 0);
+        lines.chunks(2).map(|pair| (unescape(pair[0]), unescape(pair[1]))).collect()
+    }
+
+    #[test]
+    fn matrix_html_corpus() {
+        for (html, irc) in load_corpus("matrix-html.txt") {
+            assert_eq!(html_to_irc(&html), irc, "rendering {}", html);
+        }
+    }
+
+    #[test]
+    fn mirc_codes_corpus() {
+        for (irc, html) in load_corpus("mirc-codes.txt") {
+            assert_eq!(irc_to_html(&irc).unwrap_or(strip_codes(&irc)), html, "rendering {:?}", irc);
+        }
+    }
+
+    #[test]
+    fn plain_text_stays_plain() {
+        assert_eq!(irc_to_html("just <talking> & stuff"), None);
+        assert_eq!(irc_to_html("\x02\x02"), None);
+        assert_eq!(strip_codes("\x0304,01red\x03 \x02bold\x0f"), "red bold");
+    }
+
+    #[test]
+    fn nearest_colors() {
+        assert_eq!(color_from_html("#ff0000"), Some(4));
+        assert_eq!(color_from_html("#fe0101"), Some(4));
+        assert_eq!(color_from_html("red"), None);
+    }
+}
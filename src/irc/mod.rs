@@ -17,4 +17,7 @@
 pub mod protocol;
 pub mod streams;
 pub mod security;
-mod util;
+pub mod caps;
+pub mod ctcp;
+pub mod formatting;
+pub mod util;
@@ -15,6 +15,14 @@
  */
 
 use std::str::FromStr;
+use std::collections::BTreeMap;
+use irc::util;
+
+pub type Tags = BTreeMap<String, String>;
+
+// Tags are limited to this many bytes, including the leading '@' and the
+// space that ends them.
+const MAX_TAGS_LENGTH: usize = 8191;
 
 #[derive(Debug,PartialEq,Eq)]
 pub enum Command {
@@ -22,13 +30,25 @@ pub enum Command {
     User,
     Join,
     Part,
+    Kick,
+    Invite,
     Quit,
     Ping,
     Mode,
     Pong,
     Pass,
     Privmsg,
+    Notice,
     Topic,
+    Names,
+    Who,
+    Whois,
+    List,
+    Cap,
+    Authenticate,
+    Batch,
+    Chathistory,
+    Fail,
     Numeric(u32),
     Unknown(String)
 }
@@ -39,23 +59,121 @@ impl Command {
             &Command::Nick => "NICK".to_string(),
             &Command::Join => "JOIN".to_string(),
             &Command::Part => "PART".to_string(),
+            &Command::Kick => "KICK".to_string(),
+            &Command::Invite => "INVITE".to_string(),
             &Command::Pong => "PONG".to_string(),
             &Command::Privmsg => "PRIVMSG".to_string(),
+            &Command::Notice => "NOTICE".to_string(),
             &Command::User => "USER".to_string(),
             &Command::Quit => "QUIT".to_string(),
             &Command::Ping => "PING".to_string(),
             &Command::Mode => "MODE".to_string(),
             &Command::Pass => "PASS".to_string(),
             &Command::Topic => "TOPIC".to_string(),
+            &Command::Names => "NAMES".to_string(),
+            &Command::Who => "WHO".to_string(),
+            &Command::Whois => "WHOIS".to_string(),
+            &Command::List => "LIST".to_string(),
+            &Command::Cap => "CAP".to_string(),
+            &Command::Authenticate => "AUTHENTICATE".to_string(),
+            &Command::Batch => "BATCH".to_string(),
+            &Command::Chathistory => "CHATHISTORY".to_string(),
+            &Command::Fail => "FAIL".to_string(),
             &Command::Numeric(n)=> format!("{:0>3}", n),
             &Command::Unknown(ref s) => s.clone()
         }
     }
 }
 
+fn escape_tag_value(value: &str) -> String {
+    let mut ret = String::new();
+    for c in value.chars() {
+        match c {
+            ';' => ret.push_str("\\:"),
+            ' ' => ret.push_str("\\s"),
+            '\\' => ret.push_str("\\\\"),
+            '\r' => ret.push_str("\\r"),
+            '\n' => ret.push_str("\\n"),
+            c => ret.push(c)
+        }
+    }
+    ret
+}
+
+fn unescape_tag_value(value: &str) -> String {
+    let mut ret = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some(':') => ret.push(';'),
+                Some('s') => ret.push(' '),
+                Some('r') => ret.push('\r'),
+                Some('n') => ret.push('\n'),
+                // Covers "\\" as well as invalid escapes, which just lose
+                // their backslash. A trailing backslash is dropped.
+                Some(other) => ret.push(other),
+                None => ()
+            }
+        } else {
+            ret.push(c);
+        }
+    }
+    ret
+}
+
+fn parse_tags(tags: &str) -> Tags {
+    let mut ret = Tags::new();
+    for tag in tags.split(';') {
+        if tag.len() == 0 {
+            continue;
+        }
+        match tag.find('=') {
+            Some(idx) =>
+                ret.insert(tag[..idx].to_string(), unescape_tag_value(&tag[idx+1..])),
+            None =>
+                ret.insert(tag.to_string(), String::new())
+        };
+    }
+    ret
+}
+
 impl Message {
     pub fn to_string(&self) -> String {
+        self.to_string_with(|_| true)
+    }
+
+    // Serializes the message with only the tags that keep_tag accepts, for
+    // clients that haven't negotiated every tag we know about.
+    pub fn to_string_with<F>(&self, keep_tag: F) -> String
+            where F: Fn(&str) -> bool {
         let mut ret = String::new();
+        let mut tags: Vec<String> = vec![];
+        // Account for the leading '@' and trailing space
+        let mut tags_length = 2;
+        for (key, value) in self.tags.iter() {
+            if !keep_tag(key) {
+                continue;
+            }
+            let tag = if value.len() == 0 {
+                key.clone()
+            } else {
+                format!("{}={}", key, escape_tag_value(value))
+            };
+            // Separating semicolons
+            let separator = if tags.len() == 0 { 0 } else { 1 };
+            if tags_length + separator + tag.len() > MAX_TAGS_LENGTH {
+                warn!("Dropping tag {} that doesn't fit in the tag budget", key);
+                continue;
+            }
+            tags_length += separator + tag.len();
+            tags.push(tag);
+        }
+        if tags.len() > 0 {
+            ret.push('@');
+            ret.push_str(&tags.join(";"));
+            ret.push(' ');
+        }
         match self.prefix {
             Some(ref pfx) => {
                 ret.push(':');
@@ -81,6 +199,48 @@ impl Message {
         return ret;
     }
 
+    // Timestamps are milliseconds since the epoch, as matrix hands them out.
+    // Zero means we don't know when the message happened.
+    pub fn set_server_time(&mut self, ts: u64) {
+        if ts > 0 {
+            self.tags.insert("time".to_string(), util::format_timestamp(ts));
+        }
+    }
+
+    #[cfg(test)]
+    pub fn tag(&self, key: &str) -> Option<&String> {
+        self.tags.get(key)
+    }
+
+    // Client-only tags are prefixed with '+' and are meant to be passed along
+    // to other clients untouched.
+    #[cfg(test)]
+    pub fn client_tags(&self) -> Tags {
+        let mut ret = Tags::new();
+        for (key, value) in self.tags.iter() {
+            if key.starts_with("+") {
+                ret.insert(key.clone(), value.clone());
+            }
+        }
+        ret
+    }
+
+    fn split_tags(line: &str) -> (Tags, &str) {
+        if !line.starts_with("@") {
+            return (Tags::new(), line);
+        }
+        let (tags, rest) = match line.find(' ') {
+            Some(idx) => (&line[1..idx], line[idx..].trim_left()),
+            None => (&line[1..], "")
+        };
+        if tags.len() + 2 > MAX_TAGS_LENGTH {
+            warn!("Ignoring {} bytes of tags, which is over the limit", tags.len());
+            (Tags::new(), rest)
+        } else {
+            (parse_tags(tags), rest)
+        }
+    }
+
     fn split_parts(line: &str) -> (Option<String>, &str, Option<String>) {
         let mut prefix_end = 0;
         if line.starts_with(":") {
@@ -120,8 +280,21 @@ impl Message {
         (prefix, args, suffix)
     }
 
+    // Treats the suffix as the final parameter, since clients are free to send
+    // the last argument either way.
+    pub fn param(&self, idx: usize) -> Option<String> {
+        if idx < self.args.len() {
+            Some(self.args[idx].clone())
+        } else if idx == self.args.len() {
+            self.suffix.clone()
+        } else {
+            None
+        }
+    }
+
     pub fn from_str(line: &str) -> Self {
-        let parts = Self::split_parts(line.trim());
+        let (tags, line) = Self::split_tags(line.trim());
+        let parts = Self::split_parts(line);
         let split: Vec<&str> = parts.1.split(" ").collect();
         let mut args = Vec::new();
         for s in split[1..].iter() {
@@ -129,6 +302,7 @@ impl Message {
         }
         let parsed_command: Result<Command, Command> = split[0].parse();
         Message{
+            tags: tags,
             prefix: parts.0,
             command: parsed_command.ok().unwrap(),
             args: args,
@@ -140,6 +314,7 @@ impl Message {
 impl From<Command> for Message {
     fn from(c: Command) -> Message {
         Message {
+            tags: Tags::new(),
             prefix: None,
             command: c,
             args: vec![],
@@ -157,12 +332,24 @@ impl FromStr for Command {
             "USER" => Ok(Command::User),
             "JOIN" => Ok(Command::Join),
             "PART" => Ok(Command::Part),
+            "KICK" => Ok(Command::Kick),
+            "INVITE" => Ok(Command::Invite),
             "QUIT" => Ok(Command::Quit),
             "PING" => Ok(Command::Ping),
             "MODE" => Ok(Command::Mode),
             "PASS" => Ok(Command::Pass),
             "TOPIC" => Ok(Command::Topic),
+            "NAMES" => Ok(Command::Names),
+            "WHO" => Ok(Command::Who),
+            "WHOIS" => Ok(Command::Whois),
+            "LIST" => Ok(Command::List),
+            "CAP" => Ok(Command::Cap),
+            "AUTHENTICATE" => Ok(Command::Authenticate),
+            "BATCH" => Ok(Command::Batch),
+            "CHATHISTORY" => Ok(Command::Chathistory),
+            "FAIL" => Ok(Command::Fail),
             "PRIVMSG" => Ok(Command::Privmsg),
+            "NOTICE" => Ok(Command::Notice),
             _ => Ok(Command::Unknown(s.to_string()))
         }
     }
@@ -170,6 +357,7 @@ impl FromStr for Command {
 
 #[derive(PartialEq, Debug)]
 pub struct Message {
+    pub tags: Tags,
     pub prefix: Option<String>,
     pub command: Command,
     pub args: Vec<String>,
@@ -257,9 +445,8 @@ mod tests {
         ret
     }
 
-    #[test]
-    fn identity_parse() {
-        let log = load_log_fixture("irssi.log");
+    fn assert_identity(fixture: &str) {
+        let log = load_log_fixture(fixture);
         let mut stringified: Vec<String> = vec![];
         for ref message in &log {
             stringified.push(message.to_string());
@@ -274,4 +461,65 @@ mod tests {
             assert_eq!(original, regurgitated);
         }
     }
+
+    #[test]
+    fn identity_parse() {
+        assert_identity("irssi.log");
+    }
+
+    #[test]
+    fn tagged_identity_parse() {
+        assert_identity("ircv3-tags.log");
+    }
+
+    #[test]
+    fn message_tags() {
+        let msg = Message::from_str("@time=2016-03-01T12:34:56.789Z;+draft/typing;msgid=abc :nick!nick@hostname PRIVMSG #foo :Hello");
+        assert_eq!(msg.tag("time"), Some(&"2016-03-01T12:34:56.789Z".to_owned()));
+        assert_eq!(msg.tag("+draft/typing"), Some(&"".to_owned()));
+        assert_eq!(msg.tag("msgid"), Some(&"abc".to_owned()));
+        assert_eq!(msg.prefix, Some("nick!nick@hostname".to_owned()));
+        assert_eq!(msg.command, Command::Privmsg);
+        assert_eq!(msg.args, &["#foo"]);
+        assert_eq!(msg.suffix, Some("Hello".to_owned()));
+
+        let client_tags = msg.client_tags();
+        assert_eq!(client_tags.len(), 1);
+        assert!(client_tags.contains_key("+draft/typing"));
+
+        let msg = Message::from_str("PRIVMSG #foo :@not a tag");
+        assert!(msg.tags.is_empty());
+        assert_eq!(msg.suffix, Some("@not a tag".to_owned()));
+    }
+
+    #[test]
+    fn tag_escaping() {
+        let msg = Message::from_str("@key=a\\:b\\sc\\\\d\\re\\nf\\xg\\ PING");
+        assert_eq!(msg.tag("key"), Some(&"a;b c\\d\re\nfxg".to_owned()));
+        assert_eq!(msg.command, Command::Ping);
+
+        let mut msg = Message::from(Command::Ping);
+        msg.tags.insert("key".to_owned(), "semi;colon and\\slash\r\n".to_owned());
+        assert_eq!(msg.to_string(), "@key=semi\\:colon\\sand\\\\slash\\r\\n PING");
+        assert_eq!(Message::from_str(&msg.to_string()), msg);
+    }
+
+    #[test]
+    fn tag_filtering_and_budget() {
+        let mut msg = Message::from(Command::Ping);
+        msg.tags.insert("time".to_owned(), "2016-03-01T12:34:56.789Z".to_owned());
+        msg.tags.insert("msgid".to_owned(), "abc".to_owned());
+        assert_eq!(msg.to_string_with(|tag| tag == "time"), "@time=2016-03-01T12:34:56.789Z PING");
+        assert_eq!(msg.to_string_with(|_| false), "PING");
+
+        let huge: String = (0..9000).map(|_| 'x').collect();
+        msg.tags.insert("huge".to_owned(), huge.clone());
+        let line = msg.to_string();
+        assert!(!line.contains(&huge));
+        assert!(line.starts_with("@msgid=abc;time="));
+
+        let msg = Message::from_str(&format!("@huge={} PING", huge));
+        assert!(msg.tags.is_empty());
+        assert_eq!(msg.command, Command::Ping);
+    }
 }
@@ -15,6 +15,19 @@
  */
 
 use std::fmt;
+use std::str;
+use std::io;
+use std::io::Read;
+use std::fs;
+use std::path::Path;
+use std::collections::HashMap;
+use rustc_serialize::base64::FromBase64;
+
+// Clients split AUTHENTICATE payloads into chunks of this many bytes. A chunk
+// any shorter than that (or a lone "+") ends the payload.
+const SASL_CHUNK_SIZE: usize = 400;
+// Nobody needs more than this to send a username and password.
+const SASL_MAX_PAYLOAD: usize = 8192;
 
 #[derive(Clone)]
 pub struct Auth {
@@ -28,15 +41,49 @@ impl fmt::Debug for Auth {
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SaslMechanism {
+    Plain,
+    External
+}
+
+impl SaslMechanism {
+    pub fn from_str(s: &str) -> Option<Self> {
+        match &*s.to_uppercase() {
+            "PLAIN" => Some(SaslMechanism::Plain),
+            "EXTERNAL" => Some(SaslMechanism::External),
+            _ => None
+        }
+    }
+
+    pub fn available() -> &'static str {
+        "PLAIN,EXTERNAL"
+    }
+}
+
+#[derive(Debug)]
+pub enum SaslStep {
+    Continue,
+    Done(Auth),
+    External(Option<String>),
+    Aborted,
+    Failed,
+    TooLong
+}
+
 #[derive(Debug)]
 pub struct AuthSession {
-    auth: Auth
+    auth: Auth,
+    mechanism: Option<SaslMechanism>,
+    sasl_buffer: String
 }
 
 impl AuthSession {
     pub fn new() -> Self {
         AuthSession {
-            auth: AuthSession::new_auth()
+            auth: AuthSession::new_auth(),
+            mechanism: None,
+            sasl_buffer: String::new()
         }
     }
 
@@ -57,7 +104,236 @@ impl AuthSession {
         self.auth.password = Some(password);
     }
 
+    pub fn has_username(&self) -> bool {
+        self.auth.username.is_some()
+    }
+
     pub fn set_username(&mut self, username: String) {
         self.auth.username = Some(username);
     }
+
+    pub fn in_sasl(&self) -> bool {
+        self.mechanism.is_some()
+    }
+
+    pub fn begin_sasl(&mut self, mechanism: SaslMechanism) {
+        self.mechanism = Some(mechanism);
+        self.sasl_buffer.clear();
+    }
+
+    fn end_sasl(&mut self) {
+        self.mechanism = None;
+        self.sasl_buffer.clear();
+    }
+
+    pub fn sasl_chunk(&mut self, chunk: &str) -> SaslStep {
+        let mechanism = match self.mechanism {
+            Some(m) => m,
+            None => return SaslStep::Failed
+        };
+        if chunk == "*" {
+            self.end_sasl();
+            return SaslStep::Aborted;
+        }
+        if chunk != "+" {
+            self.sasl_buffer.push_str(chunk);
+        }
+        if self.sasl_buffer.len() > SASL_MAX_PAYLOAD {
+            self.end_sasl();
+            return SaslStep::TooLong;
+        }
+        if chunk.len() == SASL_CHUNK_SIZE {
+            return SaslStep::Continue;
+        }
+        let payload = self.sasl_buffer.from_base64();
+        self.end_sasl();
+        match (payload, mechanism) {
+            (Ok(bytes), SaslMechanism::Plain) => AuthSession::decode_plain(&bytes),
+            (Ok(bytes), SaslMechanism::External) => AuthSession::decode_external(&bytes),
+            (Err(_), _) => SaslStep::Failed
+        }
+    }
+
+    // PLAIN is authzid NUL authcid NUL passwd. Matrix has no use for the
+    // authzid, so it is ignored.
+    fn decode_plain(payload: &[u8]) -> SaslStep {
+        let parts: Vec<&[u8]> = payload.split(|b| *b == 0).collect();
+        if parts.len() != 3 {
+            return SaslStep::Failed;
+        }
+        match (str::from_utf8(parts[1]), str::from_utf8(parts[2])) {
+            (Ok(username), Ok(password)) if username.len() > 0 =>
+                SaslStep::Done(Auth {
+                    username: Some(username.to_string()),
+                    password: Some(password.to_string())
+                }),
+            _ => SaslStep::Failed
+        }
+    }
+
+    // EXTERNAL carries nothing but an optional authzid; the identity itself
+    // comes from the client certificate.
+    fn decode_external(payload: &[u8]) -> SaslStep {
+        match str::from_utf8(payload) {
+            Ok("") => SaslStep::External(None),
+            Ok(authzid) => SaslStep::External(Some(authzid.to_string())),
+            Err(_) => SaslStep::Failed
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CertificateLogin {
+    pub user_id: String,
+    pub access_token: String
+}
+
+// Maps client certificate fingerprints to matrix access tokens. Each line of
+// the file is a SHA-256 fingerprint, a matrix user ID and an access token:
+//
+// 6b:1f:...:9a @alice:example.org MDAxOGxvY2F0aW9u...
+pub struct CertificateMap {
+    logins: HashMap<String, CertificateLogin>
+}
+
+impl CertificateMap {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let mut data = String::new();
+        try!(fs::File::open(path).and_then(|mut f| f.read_to_string(&mut data)));
+        Ok(CertificateMap::parse(&data))
+    }
+
+    pub fn parse(data: &str) -> Self {
+        let mut logins = HashMap::new();
+        for line in data.lines() {
+            let line = line.trim();
+            if line.len() == 0 || line.starts_with("#") {
+                continue;
+            }
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 || !fields[1].starts_with("@") || !fields[1].contains(":") {
+                warn!("Ignoring malformed certificate mapping: {}", line);
+                continue;
+            }
+            logins.insert(CertificateMap::normalize(fields[0]), CertificateLogin {
+                user_id: fields[1].to_string(),
+                access_token: fields[2].to_string()
+            });
+        }
+        CertificateMap {
+            logins: logins
+        }
+    }
+
+    // Accept fingerprints as printed by `openssl x509 -fingerprint` as well
+    // as bare hex.
+    fn normalize(fingerprint: &str) -> String {
+        fingerprint.replace(":", "").to_lowercase()
+    }
+
+    pub fn lookup(&self, fingerprint: &str) -> Option<&CertificateLogin> {
+        self.logins.get(&CertificateMap::normalize(fingerprint))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustc_serialize::base64::{ToBase64, STANDARD};
+
+    fn start_plain() -> AuthSession {
+        let mut session = AuthSession::new();
+        session.begin_sasl(SaslMechanism::Plain);
+        session
+    }
+
+    #[test]
+    fn plain_single_chunk() {
+        let mut session = start_plain();
+        let payload = "\0alice\0hunter2".as_bytes().to_base64(STANDARD);
+        match session.sasl_chunk(&payload) {
+            SaslStep::Done(auth) => {
+                assert_eq!(auth.username, Some("alice".to_string()));
+                assert_eq!(auth.password, Some("hunter2".to_string()));
+            },
+            s => panic!("Unexpected step {:?}", s)
+        }
+        assert!(!session.in_sasl());
+    }
+
+    #[test]
+    fn plain_multiple_chunks() {
+        let mut session = start_plain();
+        // Pick a password that makes the payload an exact multiple of the
+        // chunk size, so the client has to send a trailing "+".
+        let password: String = (0..580).map(|_| 'x').collect();
+        let payload = format!("\0@alice:example.org\0{}", password).as_bytes().to_base64(STANDARD);
+        assert_eq!(payload.len(), 800);
+        match session.sasl_chunk(&payload[..400]) {
+            SaslStep::Continue => (),
+            s => panic!("Unexpected step {:?}", s)
+        }
+        match session.sasl_chunk(&payload[400..]) {
+            SaslStep::Continue => (),
+            s => panic!("Unexpected step {:?}", s)
+        }
+        match session.sasl_chunk("+") {
+            SaslStep::Done(auth) => {
+                assert_eq!(auth.username, Some("@alice:example.org".to_string()));
+                assert_eq!(auth.password, Some(password));
+            },
+            s => panic!("Unexpected step {:?}", s)
+        }
+    }
+
+    #[test]
+    fn plain_bad_payloads() {
+        let mut session = start_plain();
+        match session.sasl_chunk("not base64!") {
+            SaslStep::Failed => (),
+            s => panic!("Unexpected step {:?}", s)
+        }
+
+        let mut session = start_plain();
+        match session.sasl_chunk(&"alice\0hunter2".as_bytes().to_base64(STANDARD)) {
+            SaslStep::Failed => (),
+            s => panic!("Unexpected step {:?}", s)
+        }
+
+        let mut session = start_plain();
+        match session.sasl_chunk("*") {
+            SaslStep::Aborted => (),
+            s => panic!("Unexpected step {:?}", s)
+        }
+    }
+
+    #[test]
+    fn external() {
+        let mut session = AuthSession::new();
+        session.begin_sasl(SaslMechanism::External);
+        match session.sasl_chunk("+") {
+            SaslStep::External(None) => (),
+            s => panic!("Unexpected step {:?}", s)
+        }
+
+        session.begin_sasl(SaslMechanism::External);
+        match session.sasl_chunk(&"@alice:example.org".as_bytes().to_base64(STANDARD)) {
+            SaslStep::External(Some(authzid)) => assert_eq!(authzid, "@alice:example.org"),
+            s => panic!("Unexpected step {:?}", s)
+        }
+    }
+
+    #[test]
+    fn certificate_map() {
+        let map = CertificateMap::parse("# comment\n\
+                                         AB:CD:EF @alice:example.org secret\n\
+                                         not enough fields\n\
+                                         \n\
+                                         0123 @bob:example.org hunter2\n");
+        let alice = map.lookup("abcdef").unwrap();
+        assert_eq!(alice.user_id, "@alice:example.org");
+        assert_eq!(alice.access_token, "secret");
+        assert_eq!(map.lookup("01:23").unwrap().user_id, "@bob:example.org");
+        assert!(map.lookup("not").is_none());
+    }
 }
@@ -18,19 +18,35 @@ use std::io::{Read, Write};
 use std::io;
 use mio::Evented;
 use openssl::ssl::SslStream;
+use openssl::crypto::hash;
 use mio::tcp::TcpStream;
+use rustc_serialize::hex::ToHex;
 
 use irc::util::LineReader;
 use irc::protocol::*;
 use irc::security::AuthSession;
+use irc::caps::CapSession;
 
 pub trait AsEvented {
     fn as_evented(&self) -> &Evented;
 }
 
-pub trait IrcStream: Read + Write + AsEvented + Send {}
+pub trait IrcStream: Read + Write + AsEvented + Send {
+    fn peer_fingerprint(&self) -> Option<String> {
+        None
+    }
+}
+
+impl IrcStream for SslStream<TcpStream> {
+    fn peer_fingerprint(&self) -> Option<String> {
+        self.ssl().peer_certificate().and_then(|cert| {
+            cert.fingerprint(hash::Type::SHA256)
+        }).map(|fingerprint| {
+            fingerprint.to_hex()
+        })
+    }
+}
 
-impl IrcStream for SslStream<TcpStream> {}
 impl IrcStream for TcpStream {}
 
 impl AsEvented for TcpStream {
@@ -55,7 +71,9 @@ pub struct Client {
     stream: Box<IrcStream>,
     line_reader: LineReader,
     nickname: Option<String>,
+    registered: bool,
     pub auth: AuthSession,
+    pub caps: CapSession,
 }
 
 impl Client {
@@ -64,7 +82,9 @@ impl Client {
             stream: stream,
             line_reader: LineReader::new(),
             nickname: None,
+            registered: false,
             auth: AuthSession::new(),
+            caps: CapSession::new(),
         }
     }
 
@@ -89,35 +109,93 @@ impl Client {
         self.nickname = Some(nickname);
     }
 
+    pub fn is_registered(&self) -> bool {
+        self.registered
+    }
+
+    pub fn ready_to_register(&self) -> bool {
+        !self.registered && self.nickname.is_some() && self.auth.has_username() && !self.caps.is_negotiating()
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.is_enabled(cap)
+    }
+
+    pub fn handle_cap(&mut self, message: &Message) -> io::Result<usize> {
+        let nickname = self.nickname();
+        let mut res = Ok(0);
+        for reply in self.caps.handle(&nickname, message) {
+            res = res.and(self.send(&reply));
+        }
+        res
+    }
+
+    pub fn certificate_fingerprint(&self) -> Option<String> {
+        self.stream.peer_fingerprint()
+    }
+
+    pub fn nickname(&self) -> String {
+        self.nickname.clone().unwrap_or("*".to_string())
+    }
+
+    pub fn send_numeric(&mut self, numeric: u32, args: Vec<String>, text: &str) -> io::Result<usize> {
+        let mut full_args = vec![self.nickname()];
+        full_args.extend(args);
+        self.send(&Message {
+            tags: Tags::new(),
+            prefix: Some("pto".to_string()),
+            command: Command::Numeric(numeric),
+            args: full_args,
+            suffix: Some(text.to_string())
+        })
+    }
+
     pub fn pong(&mut self) -> io::Result<usize> {
         self.send(&Message::from(Command::Pong))
     }
 
     pub fn welcome(&mut self, message: &str) -> io::Result<usize> {
         let nickname = self.nickname.clone().unwrap();
+        self.registered = true;
         self.send(&Message {
+            tags: Tags::new(),
             prefix: Some("pto".to_string()),
             command: Command::Numeric(1),
             args: vec![nickname.clone()],
             suffix: Some(format!("{} {}", message, nickname).to_string())
         }).and(self.send(&Message {
+            tags: Tags::new(),
             prefix: Some("pto".to_string()),
             command: Command::Numeric(2),
             args: vec![nickname.clone()],
             suffix: Some("Your host is running Perpetually Talking Online, the IRC frontend to Matrix.".to_string())
         })).and(self.send(&Message {
+            tags: Tags::new(),
             prefix: Some("pto".to_string()),
             command: Command::Numeric(5),
-            args: vec![nickname.clone(), "CHANTYPES=# NETWORK=matrix CHARSET=utf-8".to_string()],
+            args: vec![nickname.clone(), "CHANTYPES=# PREFIX=(ov)@+ CHANMODES=b,,, NETWORK=matrix CHARSET=utf-8 CHATHISTORY=100".to_string()],
             suffix: Some("are supported by this server".to_string())
         }))
     }
 
+    fn tag_allowed(&self, tag: &str) -> bool {
+        match tag {
+            "time" => self.caps.is_enabled("server-time"),
+            "batch" => self.caps.is_enabled("batch"),
+            _ => self.caps.is_enabled("message-tags")
+        }
+    }
+
     pub fn send(&mut self, message: &Message) -> io::Result<usize> {
+        // Batched messages still make sense on their own
+        if message.command == Command::Batch && !self.caps.is_enabled("batch") {
+            return Ok(0);
+        }
+        let line = message.to_string_with(|tag| self.tag_allowed(tag));
         if cfg!(raw_logs) {
-            trace!(">>> {}", message.to_string());
+            trace!(">>> {}", line);
         }
-        self.stream.write(&message.to_string().as_bytes())
+        self.stream.write(&line.as_bytes())
             .and(self.stream.write("\r\n".as_bytes()))
     }
 }
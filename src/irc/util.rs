@@ -17,6 +17,9 @@
 use std::io::Read;
 use std::str;
 
+// Up to 8191 bytes of IRCv3 tags, plus the classic 512 byte message.
+const MAX_LINE_LENGTH: usize = 8191 + 512;
+
 #[derive(Debug)]
 pub struct LineReader {
     linebuf: String
@@ -41,7 +44,7 @@ impl LineReader {
         let next_msg = stream.read(&mut buf);
         match next_msg {
             Ok(count) => {
-                if self.linebuf.len() + count >= 2048 {
+                if self.linebuf.len() + count >= MAX_LINE_LENGTH {
                     // FIXME: Return an error instead?
                     panic!("Too much buffer used.");
                 }
@@ -76,6 +79,81 @@ impl LineReader {
     }
 }
 
+// Formats milliseconds since the epoch the way server-time wants it, eg
+// 2016-03-01T12:34:56.789Z
+pub fn format_timestamp(ms: u64) -> String {
+    let secs = ms / 1000;
+    let days = secs / 86400;
+    let secs_of_day = secs % 86400;
+
+    // Convert days since the epoch to a civil date, from
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = z / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day,
+            secs_of_day / 3600, (secs_of_day / 60) % 60, secs_of_day % 60,
+            ms % 1000)
+}
+
+// The reverse of format_timestamp, for timestamps that clients send us.
+pub fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let timestamp = timestamp.trim_right_matches('Z');
+    let (date, time) = match timestamp.find('T') {
+        Some(idx) => (&timestamp[..idx], &timestamp[idx+1..]),
+        None => return None
+    };
+    let date: Vec<u64> = date.split('-').filter_map(|p| p.parse().ok()).collect();
+    let (time, fraction) = match time.find('.') {
+        Some(idx) => (&time[..idx], &time[idx+1..]),
+        None => (time, "0")
+    };
+    let time: Vec<u64> = time.split(':').filter_map(|p| p.parse().ok()).collect();
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+    if fraction.len() == 0 || !fraction.chars().all(|c| c.is_digit(10)) {
+        return None;
+    }
+    // The fraction is of a second, so .7 is 700ms and anything past the
+    // third digit is too fine to matter
+    let mut millis: String = fraction.chars().take(3).collect();
+    while millis.len() < 3 {
+        millis.push('0');
+    }
+    let millis: u64 = match millis.parse() {
+        Ok(m) => m,
+        Err(_) => return None
+    };
+    let (year, month, day) = (date[0], date[1], date[2]);
+    if year < 1970 || month < 1 || month > 12 || day < 1 || day > 31 {
+        return None;
+    }
+    let (hour, minute, second) = (time[0], time[1], time[2]);
+    if hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+
+    // http://howardhinnant.github.io/date_algorithms.html#days_from_civil
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y / 400;
+    let yoe = y - era * 400;
+    let mp = if month > 2 { month - 3 } else { month + 9 };
+    let doy = (153 * mp + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    Some(((days * 86400 + hour * 3600 + minute * 60 + second) * 1000) + millis)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -142,4 +220,30 @@ mod tests {
             reader.read(&mut data as &mut Read);
         }
     }
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(1456835696789), "2016-03-01T12:34:56.789Z");
+        assert_eq!(format_timestamp(951868799000), "2000-02-29T23:59:59.000Z");
+
+        assert_eq!(parse_timestamp("1970-01-01T00:00:00.000Z"), Some(0));
+        assert_eq!(parse_timestamp("2016-03-01T12:34:56.789Z"), Some(1456835696789));
+        assert_eq!(parse_timestamp("2000-02-29T23:59:59Z"), Some(951868799000));
+        assert_eq!(parse_timestamp("yesterday"), None);
+        assert_eq!(parse_timestamp("2016-13-01T12:34:56.789Z"), None);
+
+        // Fractions are of a second, however many digits they have
+        let noon = parse_timestamp("2016-03-01T12:00:00Z").unwrap();
+        assert_eq!(parse_timestamp("2016-03-01T12:00:00.7Z"), Some(noon + 700));
+        assert_eq!(parse_timestamp("2016-03-01T12:00:00.70Z"), Some(noon + 700));
+        assert_eq!(parse_timestamp("2016-03-01T12:00:00.700Z"), Some(noon + 700));
+        assert_eq!(parse_timestamp("2016-03-01T12:00:00.7000Z"), Some(noon + 700));
+        assert_eq!(parse_timestamp("2016-03-01T12:00:00.Z"), None);
+        assert_eq!(parse_timestamp("2016-03-01T12:00:00.-7Z"), None);
+
+        assert_eq!(parse_timestamp("2016-03-01T24:00:00Z"), None);
+        assert_eq!(parse_timestamp("2016-03-01T12:60:00Z"), None);
+        assert_eq!(parse_timestamp("2016-03-01T12:00:60Z"), None);
+    }
 }
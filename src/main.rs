@@ -29,19 +29,23 @@ mod matrix;
 mod bridge;
 mod ssl;
 mod dns;
+mod config;
+mod identity;
 use mio::{EventLoop,Handler,Token,EventSet,PollOpt};
 use std::thread;
 use bridge::Bridge;
 use std::env;
 use std::path::Path;
 use std::net::SocketAddr;
-use openssl::ssl::{SslContext, SslMethod};
-use openssl::x509::X509FileType;
+use openssl::ssl::{SslContext, SslMethod, SSL_VERIFY_PEER};
+use openssl::x509::{X509FileType, X509StoreContext};
 use irc::streams::{Server, AsEvented};
+use config::Config;
 
 struct IrcHandler {
     server: Box<Server>,
-    url: hyper::Url
+    url: hyper::Url,
+    config: Config
 }
 
 impl Handler for IrcHandler {
@@ -53,7 +57,7 @@ impl Handler for IrcHandler {
             SERVER => {
                 match self.server.accept() {
                     Some(client) => {
-                        let mut bridge = Bridge::new(client, self.url.clone());
+                        let mut bridge = Bridge::new(client, self.url.clone(), self.config.clone());
                         thread::spawn(move||{
                             bridge.run()
                         });
@@ -68,6 +72,12 @@ impl Handler for IrcHandler {
 
 const SERVER: Token = Token(0);
 
+// Client certificates are only used as an identity for SASL EXTERNAL, by way of
+// their fingerprint, so any certificate the client presents is acceptable.
+fn accept_client_certificate(_preverify_ok: bool, _ctx: &X509StoreContext) -> bool {
+    true
+}
+
 fn main() {
     if env::var_os("RUST_LOG") == None {
         env::set_var("RUST_LOG", "pto=info");
@@ -102,17 +112,20 @@ fn main() {
         let mut ssl = SslContext::new(SslMethod::Sslv23).expect("SSL setup failed");
         ssl.set_certificate_file(Path::new("pto.crt"), X509FileType::PEM).expect("Could not load pto.crt");
         ssl.set_private_key_file(Path::new("pto.key"), X509FileType::PEM).expect("Could not load pto.key");
+        ssl.set_verify(SSL_VERIFY_PEER, Some(accept_client_certificate));
         Box::new(ssl::SslServer::new(&addr, ssl))
     } else {
         warn!("SSL IS DISABLED!! IRC LOGINS ARE IN THE CLEAR AND UNENCRYPTED");
         Box::new(ssl::TcpServer::new(&addr))
     };
+    let config = Config::load(Path::new("pto.conf"));
     info!("Listening on {}", addr);
     info!("Using matrix URL at {}", url);
     let mut events = EventLoop::new().unwrap();
     events.register(server.as_evented(), SERVER, EventSet::all(), PollOpt::edge()).unwrap();
     events.run(&mut IrcHandler{
         server: server,
-        url: url
+        url: url,
+        config: config
     }).unwrap();
 }
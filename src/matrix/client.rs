@@ -36,9 +36,19 @@ pub enum ClientError {
     Http(hyper::Error),
     UrlNotFound(hyper::Url),
     BadStatus(hyper::status::StatusCode),
+    Matrix(hyper::status::StatusCode, String, String),
     Json(json::ParserError)
 }
 
+impl ClientError {
+    pub fn errcode(&self) -> Option<&str> {
+        match self {
+            &ClientError::Matrix(_, ref errcode, _) => Some(errcode),
+            _ => None
+        }
+    }
+}
+
 pub type Result<T = ()> = result::Result<T, ClientError>;
 
 mod http {
@@ -61,12 +71,55 @@ mod http {
                 },
                 hyper::status::StatusCode::NotFound =>
                     Err(ClientError::UrlNotFound(res.url.clone())),
-                s => Err(ClientError::BadStatus(s))
+                s => {
+                    // Matrix explains itself with an errcode when it can
+                    let error = res.read_to_string(&mut response).ok().and_then(|_| {
+                        Json::from_str(&response).ok()
+                    }).and_then(|js| {
+                        match (js.find("errcode").and_then(|e| e.as_string()),
+                               js.find("error").and_then(|e| e.as_string())) {
+                            (Some(errcode), error) =>
+                                Some((errcode.to_string(), error.unwrap_or("").to_string())),
+                            _ => None
+                        }
+                    });
+                    match error {
+                        Some((errcode, error)) => Err(ClientError::Matrix(s, errcode, error)),
+                        None => Err(ClientError::BadStatus(s))
+                    }
+                }
             }
         })
     }
 }
 
+// Room aliases and IDs are full of characters that mean something else in a
+// URL, so they need escaping before going into a path.
+fn escape(s: &str) -> String {
+    let mut ret = String::new();
+    for b in s.bytes() {
+        match b {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' =>
+                ret.push(b as char),
+            _ => ret.push_str(&format!("%{:02X}", b))
+        }
+    }
+    ret
+}
+
+fn room_endpoint(room: &model::RoomID, endpoint: &str) -> String {
+    format!("rooms/{}/{}", escape(&format!("{}", room)), endpoint)
+}
+
+// FIXME: This seems needed since hyper will pool HTTP client connections for
+// pipelining. Sometimes the server will close the pooled connection and
+// everything will catch on fire.
+fn new_http() -> hyper::client::Client {
+    let mut http = hyper::client::Client::new();
+    http.set_redirect_policy(hyper::client::RedirectPolicy::FollowAll);
+    http
+}
+
 pub struct AsyncPoll {
     http: hyper::client::Client,
     url: hyper::Url
@@ -98,18 +151,76 @@ impl AsyncPoll {
                 ret.push(events::Event::from_json(&Json::Object(p.as_object().unwrap().clone())));
             };
 
+            if let Some(account_data) = json.find_path(&["account_data", "events"]).and_then(|a| a.as_array()) {
+                for ref evt in account_data {
+                    ret.push(events::Event::from_json(evt));
+                }
+            }
+
             let joined_rooms = mjson::path(&json, "rooms.join").as_object().unwrap();
             for (id, r) in joined_rooms {
+                if let Some(prev_batch) = r.find_path(&["timeline", "prev_batch"]).and_then(|t| t.as_string()) {
+                    ret.push(events::Event {
+                        age: 0,
+                        origin_server_ts: 0,
+                        data: events::EventData::PrevBatch(model::RoomID::from_str(id), prev_batch.to_string()),
+                        id: None
+                    });
+                }
                 AsyncPoll::do_room_events(&mut ret, mjson::array(r, "state.events"), id);
                 AsyncPoll::do_room_events(&mut ret, mjson::array(r, "timeline.events"), id);
                 AsyncPoll::do_room_events(&mut ret, mjson::array(r, "account_data.events"), id);
                 AsyncPoll::do_room_events(&mut ret, mjson::array(r, "ephemeral.events"), id);
             };
 
+            // Invited rooms only come with a little bit of stripped state,
+            // which is just enough to say who invited us and where to.
+            if let Some(invited_rooms) = json.find_path(&["rooms", "invite"]).and_then(|i| i.as_object()) {
+                for (id, r) in invited_rooms {
+                    let mut inviter: Option<model::UserID> = None;
+                    let mut alias: Option<String> = None;
+                    if let Some(state) = r.find_path(&["invite_state", "events"]).and_then(|e| e.as_array()) {
+                        for evt in state {
+                            match evt.find("type").and_then(|t| t.as_string()) {
+                                Some("m.room.member") => {
+                                    if evt.find_path(&["content", "membership"]).and_then(|m| m.as_string()) == Some("invite") {
+                                        inviter = evt.find("sender").and_then(|s| s.as_string()).map(model::UserID::from_str);
+                                    }
+                                },
+                                Some("m.room.canonical_alias") => {
+                                    alias = evt.find_path(&["content", "alias"]).and_then(|a| a.as_string()).map(|a| a.to_string());
+                                },
+                                _ => ()
+                            }
+                        }
+                    }
+                    if let Some(inviter) = inviter {
+                        ret.push(events::Event {
+                            age: 0,
+                            origin_server_ts: 0,
+                            data: events::EventData::Invite(model::RoomID::from_str(id), inviter, alias),
+                            id: None
+                        });
+                    }
+                }
+            }
+
+            if let Some(left_rooms) = json.find_path(&["rooms", "leave"]).and_then(|l| l.as_object()) {
+                for (id, _) in left_rooms {
+                    ret.push(events::Event {
+                        age: 0,
+                        origin_server_ts: 0,
+                        data: events::EventData::Left(model::RoomID::from_str(id)),
+                        id: None
+                    });
+                }
+            }
+
             let next_token = mjson::string(&json, "next_batch").to_string();
 
             ret.push(events::Event {
                 age: 0,
+                origin_server_ts: 0,
                 data: events::EventData::EndOfSync(next_token),
                 id: None
             });
@@ -184,12 +295,45 @@ impl Client {
                     access: obj.get("access_token").unwrap().as_string().unwrap().to_string(),
                     refresh: obj.get("refresh_token").unwrap().as_string().unwrap().to_string()
                 });
-                let domain = self.baseurl.host().unwrap().serialize();
-                self.uid = Some(model::UserID::from_str(&format!("@{}:{}", username, domain)));
+                self.uid = Some(match obj.get("user_id") {
+                    Some(uid) => model::UserID::from_str(uid.as_string().unwrap()),
+                    None => {
+                        let domain = self.baseurl.host().unwrap().serialize();
+                        model::UserID::from_str(&format!("@{}:{}", username, domain))
+                    }
+                });
                 Ok(())
             })
     }
 
+    // The token might have been revoked since it was written down, so make
+    // sure the homeserver still takes it, and for the right user.
+    pub fn token_login(&mut self, user_id: &str, access_token: &str) -> Result {
+        debug!("Logging in to matrix with an existing access token");
+        self.token = Some(AccessToken {
+            access: access_token.to_string(),
+            refresh: String::new()
+        });
+        let url = self.url(ApiVersion::R0, "account/whoami", &HashMap::new());
+        let whoami = http::json(new_http().get(url))
+            .map(|js| js.find("user_id").and_then(|u| u.as_string()).map(|u| u.to_string()));
+        match whoami {
+            Ok(Some(ref uid)) if uid == user_id => {
+                self.uid = Some(model::UserID::from_str(user_id));
+                Ok(())
+            },
+            Ok(uid) => {
+                self.token = None;
+                Err(ClientError::Matrix(hyper::status::StatusCode::Forbidden, "M_FORBIDDEN".to_string(),
+                                        format!("Access token belongs to {:?}, not {}", uid, user_id)))
+            },
+            Err(err) => {
+                self.token = None;
+                Err(err)
+            }
+        }
+    }
+
     fn url(&self, version: ApiVersion, endpoint: &str, args: &HashMap<&str, &str>) -> hyper::Url {
         let mut ret = self.baseurl.clone();
         ret.path_mut().unwrap().append(&mut vec!["client".to_string()]);
@@ -231,6 +375,177 @@ impl Client {
         }
     }
 
+    // Fetches a page of history going backwards from the given pagination
+    // token, newest events first. Also returns the token for the next page.
+    pub fn messages(&mut self, room: &model::RoomID, from: &str, limit: usize) -> Result<(Vec<events::Event>, String)> {
+        let limit = format!("{}", limit);
+        let mut args = HashMap::new();
+        args.insert("from", from);
+        args.insert("dir", "b");
+        args.insert("limit", &*limit);
+        let url = self.url(ApiVersion::R0, &room_endpoint(room, "messages"), &args);
+        http::json(new_http().get(url)).and_then(|json| {
+            let mut ret: Vec<events::Event> = vec![];
+            AsyncPoll::do_room_events(&mut ret, mjson::array(&json, "chunk"), &format!("{}", room));
+            // The end token goes missing once we run out of history
+            let end = match json.find("end").and_then(|e| e.as_string()) {
+                Some(end) => end.to_string(),
+                None => String::new()
+            };
+            Ok((ret, end))
+        })
+    }
+
+    pub fn profile(&mut self, user: &model::UserID) -> Result<model::Profile> {
+        let url = self.url(ApiVersion::R0, &format!("profile/{}", escape(&format!("{}", user))), &HashMap::new());
+        http::json(new_http().get(url)).and_then(|js| {
+            Ok(model::Profile {
+                displayname: js.find("displayname").and_then(|d| d.as_string()).map(|d| d.to_string()),
+                avatar_url: js.find("avatar_url").and_then(|a| a.as_string()).map(|a| a.to_string())
+            })
+        })
+    }
+
+    // Starts a private chat with someone, which they still need to accept
+    pub fn create_direct_room(&mut self, user: &model::UserID) -> Result<model::RoomID> {
+        let mut d = BTreeMap::new();
+        d.insert("invite".to_string(), Json::Array(vec![Json::String(format!("{}", user))]));
+        d.insert("is_direct".to_string(), Json::Boolean(true));
+        d.insert("preset".to_string(), Json::String("trusted_private_chat".to_string()));
+        let url = self.url(ApiVersion::R0, "createRoom", &HashMap::new());
+        http::json(new_http().post(url).body(&Json::Object(d).to_string())).map(|js| {
+            model::RoomID::from_str(mjson::string(&js, "room_id"))
+        })
+    }
+
+    pub fn set_direct_rooms(&mut self, direct: &events::DirectRooms) -> Result {
+        let uid = self.uid.clone().unwrap();
+        let mut d = BTreeMap::new();
+        for (user, rooms) in direct {
+            let rooms = rooms.iter().map(|r| Json::String(format!("{}", r))).collect();
+            d.insert(format!("{}", user), Json::Array(rooms));
+        }
+        let url = self.url(ApiVersion::R0, &format!("user/{}/account_data/m.direct", escape(&format!("{}", uid))), &HashMap::new());
+        http::json(new_http().put(url).body(&Json::Object(d).to_string())).map(|_| ())
+    }
+
+    pub fn set_displayname(&mut self, name: &str) -> Result {
+        let uid = self.uid.clone().unwrap();
+        let mut d = BTreeMap::new();
+        d.insert("displayname".to_string(), Json::String(name.to_string()));
+        let url = self.url(ApiVersion::R0, &format!("profile/{}/displayname", escape(&format!("{}", uid))), &HashMap::new());
+        http::json(new_http().put(url).body(&Json::Object(d).to_string())).map(|_| ())
+    }
+
+    // Fetches a page of the homeserver's public room directory, optionally
+    // filtered by the server. Also returns the token for the next page, if
+    // there is one.
+    pub fn public_rooms(&mut self, since: Option<&str>, search: Option<&str>, limit: usize) -> Result<(Vec<model::PublicRoom>, Option<String>)> {
+        let mut d = BTreeMap::new();
+        d.insert("limit".to_string(), Json::U64(limit as u64));
+        if let Some(token) = since {
+            d.insert("since".to_string(), Json::String(token.to_string()));
+        }
+        if let Some(term) = search {
+            let mut filter = BTreeMap::new();
+            filter.insert("generic_search_term".to_string(), Json::String(term.to_string()));
+            d.insert("filter".to_string(), Json::Object(filter));
+        }
+        let url = self.url(ApiVersion::R0, "publicRooms", &HashMap::new());
+        http::json(new_http().post(url).body(&Json::Object(d).to_string())).and_then(|js| {
+            let mut rooms = vec![];
+            for r in mjson::array(&js, "chunk") {
+                let mut aliases = vec![];
+                if let Some(list) = r.find("aliases").and_then(|a| a.as_array()) {
+                    for alias in list {
+                        if let Some(a) = alias.as_string() {
+                            aliases.push(a.to_string());
+                        }
+                    }
+                }
+                rooms.push(model::PublicRoom {
+                    id: model::RoomID::from_str(mjson::string(r, "room_id")),
+                    aliases: aliases,
+                    canonical_alias: r.find("canonical_alias").and_then(|a| a.as_string()).map(|a| a.to_string()),
+                    name: r.find("name").and_then(|n| n.as_string()).map(|n| n.to_string()),
+                    topic: r.find("topic").and_then(|t| t.as_string()).map(|t| t.to_string()),
+                    num_joined_members: r.find("num_joined_members").and_then(|n| n.as_u64()).unwrap_or(0)
+                });
+            }
+            let next = js.find("next_batch").and_then(|n| n.as_string()).map(|n| n.to_string());
+            Ok((rooms, next))
+        })
+    }
+
+    pub fn join(&mut self, room: &str) -> Result<model::RoomID> {
+        let url = self.url(ApiVersion::R0, &format!("join/{}", escape(room)), &HashMap::new());
+        http::json(new_http().post(url).body("{}")).and_then(|js| {
+            Ok(model::RoomID::from_str(mjson::string(&js, "room_id")))
+        })
+    }
+
+    pub fn leave(&mut self, room: &model::RoomID, reason: Option<&str>) -> Result {
+        let mut d = BTreeMap::new();
+        if let Some(r) = reason {
+            d.insert("reason".to_string(), Json::String(r.to_string()));
+        }
+        let url = self.url(ApiVersion::R0, &room_endpoint(room, "leave"), &HashMap::new());
+        http::json(new_http().post(url).body(&Json::Object(d).to_string())).map(|_| ())
+    }
+
+    // Shared by invite, kick, ban and unban, which all take the same body
+    fn moderate(&mut self, room: &model::RoomID, endpoint: &str, user: &model::UserID, reason: Option<&str>) -> Result {
+        let mut d = BTreeMap::new();
+        d.insert("user_id".to_string(), Json::String(format!("{}", user)));
+        if let Some(r) = reason {
+            d.insert("reason".to_string(), Json::String(r.to_string()));
+        }
+        let url = self.url(ApiVersion::R0, &room_endpoint(room, endpoint), &HashMap::new());
+        http::json(new_http().post(url).body(&Json::Object(d).to_string())).map(|_| ())
+    }
+
+    pub fn invite(&mut self, room: &model::RoomID, user: &model::UserID) -> Result {
+        self.moderate(room, "invite", user, None)
+    }
+
+    pub fn kick(&mut self, room: &model::RoomID, user: &model::UserID, reason: Option<&str>) -> Result {
+        self.moderate(room, "kick", user, reason)
+    }
+
+    pub fn ban(&mut self, room: &model::RoomID, user: &model::UserID, reason: Option<&str>) -> Result {
+        self.moderate(room, "ban", user, reason)
+    }
+
+    pub fn unban(&mut self, room: &model::RoomID, user: &model::UserID) -> Result {
+        self.moderate(room, "unban", user, None)
+    }
+
+    pub fn forget(&mut self, room: &model::RoomID) -> Result {
+        let url = self.url(ApiVersion::R0, &room_endpoint(room, "forget"), &HashMap::new());
+        http::json(new_http().post(url).body("{}")).map(|_| ())
+    }
+
+    pub fn set_state(&mut self, evt: events::EventData, state_key: &str) -> Result<model::EventID> {
+        let url = match evt {
+            events::EventData::Room(ref id, _) => {
+                let endpoint = if state_key.len() == 0 {
+                    format!("state/{}", evt.type_str())
+                } else {
+                    format!("state/{}/{}", evt.type_str(), escape(state_key))
+                };
+                self.url(ApiVersion::R0, &room_endpoint(id, &endpoint), &HashMap::new())
+            },
+            _ => panic!("Don't know where to send {}", evt.to_json())
+        };
+        trace!("Setting state at {:?}", url);
+        http::json(new_http().put(url).body(&format!("{}", evt.to_json()))).and_then(|response| {
+            if cfg!(raw_logs) {
+                trace!(">>> {} {:?}", evt.to_json(), response);
+            }
+            Ok(model::EventID::from_str(mjson::string(&response, "event_id")))
+        })
+    }
+
     pub fn send(&mut self, evt: events::EventData) -> Result<model::EventID> {
         self.next_id += 1;
         match evt {
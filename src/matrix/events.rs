@@ -16,15 +16,18 @@
 
 use rustc_serialize::json::Json;
 use rustc_serialize::json;
+use std::collections::BTreeMap;
 use matrix::json as mjson;
 use matrix::model;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum MembershipAction {
     Join,
     Leave,
     Ban,
     Invite,
+    // Anything newer than us, like knock
+    Other(String)
 }
 
 impl MembershipAction {
@@ -34,27 +37,210 @@ impl MembershipAction {
             "leave" => MembershipAction::Leave,
             "ban" => MembershipAction::Ban,
             "invite" => MembershipAction::Invite,
-            _ => panic!("unknown membership action {:?}", s)
+            other => MembershipAction::Other(other.to_string())
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageType {
+    Text,
+    Notice,
+    Emote,
+    Other(String)
+}
+
+impl MessageType {
+    pub fn from_str(s: &str) -> Self {
+        match s {
+            "m.text" => MessageType::Text,
+            "m.notice" => MessageType::Notice,
+            "m.emote" => MessageType::Emote,
+            other => MessageType::Other(other.to_string())
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            &MessageType::Text => "m.text",
+            &MessageType::Notice => "m.notice",
+            &MessageType::Emote => "m.emote",
+            &MessageType::Other(ref other) => other
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct MessageContent {
+    pub msgtype: MessageType,
+    pub body: String,
+    // Only set for org.matrix.custom.html, the one format anyone sends
+    pub formatted_body: Option<String>
+}
+
+impl MessageContent {
+    pub fn new(msgtype: MessageType, body: String) -> Self {
+        MessageContent {
+            msgtype: msgtype,
+            body: body,
+            formatted_body: None
+        }
+    }
+
+    pub fn from_json(content: &Json) -> Self {
+        MessageContent {
+            msgtype: MessageType::from_str(content.find("msgtype").and_then(|t| t.as_string()).unwrap_or("m.text")),
+            body: content.find("body").and_then(|b| b.as_string()).unwrap_or("").to_string(),
+            formatted_body: match content.find("format").and_then(|f| f.as_string()) {
+                Some("org.matrix.custom.html") =>
+                    content.find("formatted_body").and_then(|b| b.as_string()).map(|b| b.to_string()),
+                _ => None
+            }
+        }
+    }
+}
+
+// The user a membership event is about is the state_key, which is only the
+// sender when people join or leave on their own.
+#[derive(Debug)]
+pub struct Membership {
+    pub target: model::UserID,
+    pub sender: model::UserID,
+    pub action: MembershipAction,
+    pub displayname: Option<String>,
+    pub prev_action: Option<MembershipAction>,
+    pub reason: Option<String>
+}
+
+impl Membership {
+    pub fn from_json(json: &Json) -> Self {
+        // Synced events carry prev_content in unsigned, older APIs put it at
+        // the top level
+        let prev_content = json.find_path(&["unsigned", "prev_content"])
+            .or(json.find("prev_content"));
+        let content = json.find("content");
+        Membership {
+            target: model::UserID::from_str(mjson::string(json, "state_key")),
+            sender: model::UserID::from_str(mjson::string(json, "sender")),
+            action: MembershipAction::from_str(mjson::string(json, "content.membership")),
+            displayname: Membership::content_string(content, "displayname"),
+            prev_action: Membership::content_string(prev_content, "membership").map(|m| MembershipAction::from_str(&m)),
+            reason: Membership::content_string(content, "reason")
+        }
+    }
+
+    fn content_string(content: Option<&Json>, key: &str) -> Option<String> {
+        content.and_then(|c| c.find(key)).and_then(|v| v.as_string()).map(|v| v.to_string())
+    }
+
+    // Someone else taking the user out of the room. Lifting a ban looks just
+    // like a kick, except for what the membership was before.
+    pub fn is_kick(&self) -> bool {
+        self.action == MembershipAction::Leave && self.sender != self.target && !self.is_unban()
+    }
+
+    pub fn is_unban(&self) -> bool {
+        self.action == MembershipAction::Leave && self.prev_action == Some(MembershipAction::Ban)
+    }
+}
+
+// Keeps the whole content around, so that changing a user's level doesn't
+// clobber settings we don't know about when it gets sent back.
+#[derive(Debug, Clone)]
+pub struct PowerLevels {
+    content: json::Object
+}
+
+impl PowerLevels {
+    pub fn new() -> Self {
+        PowerLevels {
+            content: json::Object::new()
+        }
+    }
+
+    pub fn from_json(content: &Json) -> Self {
+        PowerLevels {
+            content: match content.as_object() {
+                Some(obj) => obj.clone(),
+                None => json::Object::new()
+            }
+        }
+    }
+
+    fn as_level(json: &Json) -> Option<i64> {
+        json.as_i64().or(json.as_f64().map(|f| f as i64))
+    }
+
+    fn level(&self, key: &str, default: i64) -> i64 {
+        self.content.get(key).and_then(PowerLevels::as_level).unwrap_or(default)
+    }
+
+    pub fn users_default(&self) -> i64 {
+        self.level("users_default", 0)
+    }
+
+    pub fn state_default(&self) -> i64 {
+        self.level("state_default", 50)
+    }
+
+    pub fn ban(&self) -> i64 {
+        self.level("ban", 50)
+    }
+
+    pub fn kick(&self) -> i64 {
+        self.level("kick", 50)
+    }
+
+    pub fn invite(&self) -> i64 {
+        self.level("invite", 50)
+    }
+
+    pub fn user_level(&self, user: &model::UserID) -> i64 {
+        match self.content.get("users").and_then(|u| u.find(&format!("{}", user))) {
+            Some(level) => PowerLevels::as_level(level).unwrap_or(self.users_default()),
+            None => self.users_default()
+        }
+    }
+
+    pub fn set_user_level(&mut self, user: &model::UserID, level: i64) {
+        let default = self.users_default();
+        let mut users = match self.content.get("users").and_then(|u| u.as_object()) {
+            Some(u) => u.clone(),
+            None => json::Object::new()
+        };
+        if level == default {
+            users.remove(&format!("{}", user));
+        } else {
+            users.insert(format!("{}", user), Json::I64(level));
+        }
+        self.content.insert("users".to_string(), Json::Object(users));
+    }
+
+    pub fn to_json(&self) -> Json {
+        Json::Object(self.content.clone())
+    }
+}
+
 #[derive(Debug)]
 pub enum RoomEvent {
     CanonicalAlias(String),
     JoinRules(String),
-    Membership(model::UserID, MembershipAction),
+    Membership(Membership),
     HistoryVisibility(String),
     Create,
     Aliases(Vec<String>),
-    Message(model::UserID, String),
-    PowerLevels,
+    Message(model::UserID, MessageContent),
+    PowerLevels(model::UserID, PowerLevels),
     Name(model::UserID, String),
     Avatar(model::UserID, String),
     Topic(model::UserID, String),
     Unknown(String, Json)
 }
 
+// The m.direct account data, which lists the rooms that are DMs with each
+// user.
+pub type DirectRooms = BTreeMap<model::UserID, Vec<model::RoomID>>;
+
 #[derive(Debug)]
 pub struct TypingEvent {
     pub users: Vec<model::UserID>,
@@ -66,7 +252,14 @@ pub enum EventData {
     Room(model::RoomID, RoomEvent),
     Typing(TypingEvent),
     Presence(PresenceEvent),
+    Direct(DirectRooms),
     Unknown(String, Json),
+    // An invite to a room we aren't in yet, who sent it, and the room's
+    // canonical alias if it has one
+    Invite(model::RoomID, model::UserID, Option<String>),
+    // A room we left, or an invite that was rejected or withdrawn
+    Left(model::RoomID),
+    PrevBatch(model::RoomID, String),
     EndOfSync(String)
 }
 
@@ -79,7 +272,7 @@ impl EventData {
                 "m.room.canonical_alias".to_string(),
             &EventData::Room(_, RoomEvent::JoinRules(_)) =>
                 "m.room.join_rules".to_string(),
-            &EventData::Room(_, RoomEvent::Membership(_, _)) =>
+            &EventData::Room(_, RoomEvent::Membership(_)) =>
                 "m.room.member".to_string(),
             &EventData::Room(_, RoomEvent::HistoryVisibility(_)) =>
                 "m.room.history_visibility".to_string(),
@@ -87,7 +280,7 @@ impl EventData {
                 "m.room.create".to_string(),
             &EventData::Room(_, RoomEvent::Aliases(_)) =>
                 "m.room.aliases".to_string(),
-            &EventData::Room(_, RoomEvent::PowerLevels) =>
+            &EventData::Room(_, RoomEvent::PowerLevels(_, _)) =>
                 "m.room.power_levels".to_string(),
             &EventData::Room(_, RoomEvent::Name(_, _)) =>
                 "m.room.name".to_string(),
@@ -101,7 +294,14 @@ impl EventData {
                 "m.typing".to_string(),
             &EventData::Presence(_) =>
                 "m.presence".to_string(),
+            &EventData::Direct(_) =>
+                "m.direct".to_string(),
             &EventData::Unknown(ref unknown_type, _) => unknown_type.clone(),
+            &EventData::Invite(_, _, _) =>
+                "m.room.member".to_string(),
+            &EventData::Left(_) =>
+                "m.room.member".to_string(),
+            &EventData::PrevBatch(_, _) => panic!("PrevBatch is a special value"),
             &EventData::EndOfSync(_) => panic!("EndOfSync is a special value")
         }
     }
@@ -111,14 +311,24 @@ impl EventData {
         match self {
             &EventData::Room(ref _id, ref evt) => {
                 match evt {
-                    &RoomEvent::Message(_, ref text) => {
-                        ret.insert("msgtype".to_string(), json::Json::String("m.text".to_string()));
-                        ret.insert("body".to_string(), json::Json::String(text.clone()));
+                    &RoomEvent::Message(_, ref content) => {
+                        ret.insert("msgtype".to_string(), json::Json::String(content.msgtype.as_str().to_string()));
+                        ret.insert("body".to_string(), json::Json::String(content.body.clone()));
+                        if let Some(ref html) = content.formatted_body {
+                            ret.insert("format".to_string(), json::Json::String("org.matrix.custom.html".to_string()));
+                            ret.insert("formatted_body".to_string(), json::Json::String(html.clone()));
+                        }
+                    },
+                    &RoomEvent::Topic(_, ref topic) => {
+                        ret.insert("topic".to_string(), json::Json::String(topic.clone()));
                     },
-                    _ => panic!("Can only serialize m.room.message events :(")
+                    &RoomEvent::PowerLevels(_, ref levels) => {
+                        return levels.to_json();
+                    },
+                    _ => panic!("Can only serialize messages, topics and power levels :(")
                 }
             },
-            _ => panic!("Can only serialize m.room.message events :(")
+            _ => panic!("Can only serialize room events :(")
         }
         json::Json::Object(ret)
     }
@@ -127,6 +337,7 @@ impl EventData {
 #[derive(Debug)]
 pub struct Event {
     pub age: u64,
+    pub origin_server_ts: u64,
     pub id: Option<model::EventID>,
     pub data: EventData
 }
@@ -143,6 +354,10 @@ impl Event {
             Some(a) => a.as_u64().unwrap(),
             None => 0
         };
+        let origin_server_ts = match json.find("origin_server_ts") {
+            Some(ts) => ts.as_u64().unwrap_or(0),
+            None => 0
+        };
         let tokens: Vec<&str> = mjson::string(json, "type").split(".").collect();
         let id = match json.as_object().unwrap().get("event_id") {
             Some(i) => Some(model::EventID::from_str(i.as_string().unwrap())),
@@ -151,12 +366,14 @@ impl Event {
         if tokens[0] != "m" {
             Event {
                 age: age,
+                origin_server_ts: origin_server_ts,
                 id: id,
                 data: EventData::Unknown(json.as_object().unwrap().get("type").unwrap().as_string().unwrap().to_string(), json.clone()),
             }
         } else {
             Event {
                 age: age,
+                origin_server_ts: origin_server_ts,
                 id: id,
                 data: match tokens[1] {
                     "room" =>
@@ -171,6 +388,26 @@ impl Event {
                             presence: mjson::string(json, "content.presence").to_string(),
                             user: model::UserID::from_str(mjson::string(json, "sender"))
                         }),
+                    // Some clients file DMs with 3PIDs under their email
+                    // address instead of a user ID, which we can't use.
+                    "direct" => {
+                        let mut direct = DirectRooms::new();
+                        let is_id = |id: &str, sigil: &str| id.starts_with(sigil) && id.contains(":");
+                        if let Some(content) = json.find("content").and_then(|c| c.as_object()) {
+                            for (user, rooms) in content {
+                                if !is_id(user, "@") {
+                                    continue;
+                                }
+                                let rooms = rooms.as_array().map(|r| {
+                                    r.iter().filter_map(|id| id.as_string())
+                                        .filter(|id| is_id(id, "!"))
+                                        .map(model::RoomID::from_str).collect()
+                                }).unwrap_or(vec![]);
+                                direct.insert(model::UserID::from_str(user), rooms);
+                            }
+                        }
+                        EventData::Direct(direct)
+                    },
                     e =>
                         EventData::Unknown(e.to_string(), json.clone())
                 }
@@ -201,7 +438,7 @@ impl Event {
                         }
                     },
                 "member" =>
-                    RoomEvent::Membership(model::UserID::from_str(mjson::string(json, "sender")), MembershipAction::from_str(mjson::string(json, "content.membership"))),
+                    RoomEvent::Membership(Membership::from_json(json)),
                 "history_visibility" =>
                     RoomEvent::HistoryVisibility(mjson::string(json, "content.history_visibility").to_string()),
                 "create" =>
@@ -215,9 +452,9 @@ impl Event {
                     RoomEvent::Aliases(alias_list)
                 },
                 "power_levels" =>
-                    RoomEvent::PowerLevels,
+                    RoomEvent::PowerLevels(model::UserID::from_str(mjson::string(json, "sender")), PowerLevels::from_json(mjson::path(json, "content"))),
                 "message" =>
-                    RoomEvent::Message(model::UserID::from_str(mjson::string(json, "sender")), mjson::string(json, "content.body").to_string()),
+                    RoomEvent::Message(model::UserID::from_str(mjson::string(json, "sender")), MessageContent::from_json(mjson::path(json, "content"))),
                 "name" =>
                     RoomEvent::Name(model::UserID::from_str(mjson::string(json, "sender")), mjson::string(json, "content.name").to_string()),
                 "topic" =>
@@ -229,3 +466,77 @@ impl Event {
         )
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use matrix::model::{RoomID, UserID};
+    use rustc_serialize::json::Json;
+
+    fn membership(json: &str) -> Membership {
+        Membership::from_json(&Json::from_str(json).unwrap())
+    }
+
+    #[test]
+    fn message_types() {
+        let notice = MessageContent::from_json(&Json::from_str(r#"{"msgtype": "m.notice", "body": "beep"}"#).unwrap());
+        assert_eq!(notice.msgtype, MessageType::Notice);
+        assert_eq!(notice.body, "beep");
+        let image = MessageContent::from_json(&Json::from_str(r#"{"msgtype": "m.image", "body": "cat.png"}"#).unwrap());
+        assert_eq!(image.msgtype, MessageType::Other("m.image".to_string()));
+        assert_eq!(image.formatted_body, None);
+        let html = MessageContent::from_json(&Json::from_str(r#"{"msgtype": "m.text", "body": "hi", "format": "org.matrix.custom.html", "formatted_body": "<b>hi</b>"}"#).unwrap());
+        assert_eq!(html.formatted_body, Some("<b>hi</b>".to_string()));
+
+        let evt = EventData::Room(RoomID::from_str("!abc:example.org"),
+                                  RoomEvent::Message(UserID::from_str("@bot:example.org"), notice));
+        assert_eq!(evt.to_json().find("msgtype").and_then(|t| t.as_string()), Some("m.notice"));
+    }
+
+    #[test]
+    fn membership_targets_state_key() {
+        let invite = membership(r#"{"sender": "@mod:example.org", "state_key": "@alice:example.org",
+                                    "content": {"membership": "invite"}}"#);
+        assert_eq!(invite.target, UserID::from_str("@alice:example.org"));
+        assert_eq!(invite.sender, UserID::from_str("@mod:example.org"));
+        assert_eq!(invite.action, MembershipAction::Invite);
+
+        let kick = membership(r#"{"sender": "@mod:example.org", "state_key": "@alice:example.org",
+                                  "content": {"membership": "leave", "reason": "spam"},
+                                  "unsigned": {"prev_content": {"membership": "join", "displayname": "Alice"}}}"#);
+        assert!(kick.is_kick());
+        assert!(!kick.is_unban());
+        assert_eq!(kick.reason, Some("spam".to_string()));
+        assert_eq!(kick.prev_action, Some(MembershipAction::Join));
+
+        let knock = membership(r#"{"sender": "@alice:example.org", "state_key": "@alice:example.org",
+                                   "content": {"membership": "leave"},
+                                   "unsigned": {"prev_content": {"membership": "knock"}}}"#);
+        assert_eq!(knock.prev_action, Some(MembershipAction::Other("knock".to_string())));
+        assert!(!knock.is_kick());
+
+        let unban = membership(r#"{"sender": "@mod:example.org", "state_key": "@alice:example.org",
+                                   "content": {"membership": "leave"},
+                                   "prev_content": {"membership": "ban"}}"#);
+        assert!(unban.is_unban());
+        assert!(!unban.is_kick());
+
+        let part = membership(r#"{"sender": "@alice:example.org", "state_key": "@alice:example.org",
+                                  "content": {"membership": "leave"}}"#);
+        assert!(!part.is_kick());
+        assert_eq!(part.prev_action, None);
+    }
+
+    #[test]
+    fn direct_rooms_skip_junk() {
+        let direct = |json: &str| match Event::from_json(&Json::from_str(json).unwrap()).data {
+            EventData::Direct(direct) => direct,
+            other => panic!("Expected m.direct, got {:?}", other)
+        };
+        let rooms = direct(r#"{"type": "m.direct", "content": {"@bob:example.org": ["!dm:example.org", "oops"],
+                                                               "bob@example.org": ["!3pid:example.org"]}}"#);
+        assert_eq!(rooms.len(), 1);
+        assert_eq!(rooms[&UserID::from_str("@bob:example.org")], vec![RoomID::from_str("!dm:example.org")]);
+        assert!(direct(r#"{"type": "m.direct", "content": []}"#).is_empty());
+    }
+}
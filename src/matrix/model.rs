@@ -87,3 +87,19 @@ impl EventID {
         }
     }
 }
+
+#[derive(Clone, Debug)]
+pub struct Profile {
+    pub displayname: Option<String>,
+    pub avatar_url: Option<String>
+}
+
+#[derive(Clone, Debug)]
+pub struct PublicRoom {
+    pub id: RoomID,
+    pub aliases: Vec<String>,
+    pub canonical_alias: Option<String>,
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub num_joined_members: u64
+}